@@ -3,7 +3,7 @@ use openssl::sha::Sha256;
 use primitive_types::H256;
 use std::{hash::Hash, collections::BTreeMap};
 
-use crate::p2p::key::NodeID;
+use crate::{ledger::amount::Amount, p2p::key::NodeID};
 
 #[derive(Debug,Copy,Clone)]
 pub enum AuctionState {
@@ -19,7 +19,7 @@ pub struct Auction {
 }
 
 impl Auction {
-    pub fn new( title: String, seller: NodeID,duration : i64, initial_value: f32)  -> Auction {
+    pub fn new( title: String, seller: NodeID,duration : i64, initial_value: Amount)  -> Auction {
         let starting_time = DateTime::from(Utc::now());
         let auction_id = gen_auction_id(&title,seller,starting_time);
         let info = AuctionInfo::new(title,seller,starting_time,initial_value,duration);
@@ -34,7 +34,7 @@ impl Auction {
         self.auction_id.clone()
     }
 /*  UNUSED
-    pub fn bid(&mut self, bid_amout: f32, bidder: NodeID) -> Result<(),&str> {
+    pub fn bid(&mut self, bid_amout: Amount, bidder: NodeID) -> Result<(),&str> {
         self.info.bid(bid_amout, bidder)
     } 
 */
@@ -61,15 +61,15 @@ impl Eq for Auction {}
 pub struct AuctionInfo {
     title: String,
     seller: NodeID,
-    _initial_price: f32,
-    current_price: f32,
+    _initial_price: Amount,
+    current_price: Amount,
     highest_bidder: Option<NodeID>,
     _starting_time: DateTime<Utc>,
     _time_remaining: Duration,
 }
 
 impl AuctionInfo {
-    pub fn new(title: String, seller: NodeID, _starting_time: DateTime<Utc>, _initial_price: f32, time: i64)-> AuctionInfo {
+    pub fn new(title: String, seller: NodeID, _starting_time: DateTime<Utc>, _initial_price: Amount, time: i64)-> AuctionInfo {
         AuctionInfo{
             title,
             seller,
@@ -89,7 +89,7 @@ impl AuctionInfo {
         self.seller
     }
 
-    pub fn get_initial_price(&self) -> f32 {
+    pub fn get_initial_price(&self) -> Amount {
         self.initial_price
     }
 
@@ -101,7 +101,7 @@ impl AuctionInfo {
         self.starting_time
     }
 
-    pub fn bid(&mut self, bid_amout: f32, bidder: NodeID) -> Result<(),&'static str> {
+    pub fn bid(&mut self, bid_amout: Amount, bidder: NodeID) -> Result<(),&'static str> {
         if self.current_price >= bid_amout {
             Err("bid must be greater than current price")
         } else {
@@ -120,7 +120,7 @@ pub struct AuctionGossip {
     title: String,
     seller: NodeID,
     buyer: NodeID,
-    current_price: f32,
+    current_price: Amount,
     state: AuctionState,
 }
 
@@ -136,7 +136,7 @@ impl AuctionGossip{
         }
     }
 
-    pub fn new(auction_id: H256, title: String, buyer: NodeID,current_price: f32, state: AuctionState, seller: NodeID) -> AuctionGossip {
+    pub fn new(auction_id: H256, title: String, buyer: NodeID,current_price: Amount, state: AuctionState, seller: NodeID) -> AuctionGossip {
         AuctionGossip {
             auction_id,
             state,
@@ -155,7 +155,7 @@ impl AuctionGossip{
         self.seller.clone()
     }
 
-    pub fn get_price(&self) -> f32 {
+    pub fn get_price(&self) -> Amount {
         self.current_price
     }
 
@@ -174,7 +174,7 @@ impl AuctionGossip{
         }
     }
 
-    pub fn bid(&mut self, bid_amout: f32,buyer: NodeID) -> Result<AuctionGossip,&'static str> {
+    pub fn bid(&mut self, bid_amout: Amount,buyer: NodeID) -> Result<AuctionGossip,&'static str> {
         if self.current_price >= bid_amout {
             Err("bid must be greater than current price")
         } else {
@@ -1,6 +1,7 @@
 use primitive_types::H256;
+use tokio::sync::broadcast::{self, error::TryRecvError};
 
-use crate::ledger::block::Data;
+use crate::ledger::{amount::Amount, block::{Data, Reorg}};
 use crate::p2p::client::Client;
 use crate::p2p::kad::KadNode;
 use crate::p2p::key::NodeID;
@@ -8,34 +9,153 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use super::auction::{Auction,  AuctionGossip, Slotmap};
 
-#[derive(Debug,Clone)]
+#[derive(Debug)]
 pub struct AuctionPeer {
     pub client: Client,
     subscribed_auctions: HashMap<NodeID, Vec<AuctionGossip>>,
     my_auctions: HashMap<H256, Vec<NodeID>>,
     known_auctions: Slotmap,
+    reorgs: broadcast::Receiver<Reorg>,
 }
 
 impl AuctionPeer{
     pub fn new(node : Arc<KadNode>) -> AuctionPeer {
+            let reorgs = node.subscribe_reorgs();
             AuctionPeer{
                 client : Client::new(node),
                 subscribed_auctions: HashMap::new(),
                 my_auctions: HashMap::new(),
                 known_auctions: Slotmap::new(),
+                reorgs,
         }
     }
 
+    // reports settlements of our own and subscribed auctions that were reverted or confirmed since the last call
+    pub fn process_reorgs(&mut self) {
+        loop {
+            let reorg = match self.reorgs.try_recv() {
+                Ok(reorg) => reorg,
+                Err(TryRecvError::Lagged(missed)) => {
+                    println!("Missed {} blockchain updates", missed);
+                    continue;
+                },
+                Err(_) => return,
+            };
+
+            for block in reorg.disconnected.iter() {
+                for data in block.transactions.iter().filter(|t| self.is_relevant(t.get_auction_id())) {
+                    println!("Settlement of auction {:?} was reverted (block {} disconnected)", data.get_auction_id(), block.id);
+                }
+            }
+            for block in reorg.connected.iter() {
+                for data in block.transactions.iter().filter(|t| self.is_relevant(t.get_auction_id())) {
+                    println!("Auction {:?} settled in block {}", data.get_auction_id(), block.id);
+                }
+            }
+        }
+    }
+
+    fn is_relevant(&self, auction_id: H256) -> bool {
+        self.my_auctions.contains_key(&auction_id)
+            || self.subscribed_auctions.values().flatten().any(|a| a.get_auction_id() == auction_id)
+    }
+
     // only an exemple to test the blockchain
-    pub async fn fulfill_transaction(&self,index: i32) {
+    pub async fn fulfill_transaction(&self,index: i32, fee: Amount) {
         let id = self.known_auctions.get(index).unwrap().clone();
         if self.my_auctions.contains_key(&id.get_auction_id()){
-        let data = Data::from_auction(id);
-        let _ = self.client.broadcast_transaction(data).await;
+        let data = Data::from_auction(id).with_fee(fee);
+        if let Err(e) = self.client.settle(data).await {
+            println!("Could not settle the auction: {}", e);
+        }
+        }
+    }
+
+    // light clients don't have the blocks, so they get the proof from full nodes
+    pub async fn prove_settlement(&self, index: i32) {
+        let auction_id = match self.known_auctions.get(index) {
+            Some(auction) => auction.get_auction_id(),
+            None => return println!("Invalid auction"),
+        };
+        let proven = match self.client.prove_settlement(auction_id) {
+            Some(proof) => self.client.verify_inclusion(&proof).ok().map(|confirmations| (proof, confirmations)),
+            None => self.client.request_proof(auction_id).await,
+        };
+        match proven {
+            Some((proof, confirmations)) => {
+                let data = &proof.transaction;
+                let ours = data.get_seller() == self.client.get_uid() || data.get_buyer() == self.client.get_uid();
+                println!("Settlement {:?} in block {} ({:?}), {} confirmations", data, proof.height, proof.block_hash, confirmations);
+                println!("Proof: {:?}, ours: {}", proof.proof, ours);
+            },
+            None => println!("Auction not settled in the blockchain"),
+        }
+    }
+
+    pub fn find_settlement(&self, index: i32) {
+        if self.client.is_light() {
+            return println!("Not available on a light client, use prove_settlement");
+        }
+        let auction = match self.known_auctions.get(index) {
+            Some(auction) => auction,
+            None => return println!("Invalid auction"),
+        };
+        match self.client.find_settlement(auction.get_auction_id()) {
+            Some((height, data)) => println!("Settled in block {}: {:?}", height, data),
+            None => println!("Auction not settled in the blockchain"),
+        }
+    }
+
+    pub fn snapshot(&self, height: u64) {
+        match self.client.snapshot(height) {
+            Ok(path) => println!("Snapshot at height {} written to {}", height, path),
+            Err(e) => println!("Could not take a snapshot: {}", e),
+        }
+    }
+
+    // balance of the given account, or of this node
+    pub fn balance(&self, account: Option<&str>) {
+        if self.client.is_light() {
+            return println!("Not available on a light client");
+        }
+        let account = match self.parse_account(account) {
+            Some(account) => account,
+            None => return println!("Invalid account"),
+        };
+        let (balance, height) = self.client.balance(account);
+        println!("Balance of {:?}: {} (at block {})", account, balance, height);
+    }
+
+    // confirmed transactions of the given account, or of this node
+    pub fn account_transactions(&self, account: Option<&str>) {
+        if self.client.is_light() {
+            return println!("Not available on a light client");
+        }
+        let account = match self.parse_account(account) {
+            Some(account) => account,
+            None => return println!("Invalid account"),
+        };
+        let transactions = self.client.account_transactions(account);
+        if transactions.is_empty() {
+            return println!("No transactions for {:?}", account);
+        }
+        for (height, data) in transactions {
+            println!("Block {}: {:?}", height, data);
+        }
+    }
+
+    // a hex NodeID, this node if none is given
+    fn parse_account(&self, account: Option<&str>) -> Option<NodeID> {
+        match account {
+            None => Some(self.client.get_uid()),
+            Some(hex_id) => match hex::decode(hex_id) {
+                Ok(bytes) if bytes.len() == 32 => Some(NodeID::from_vec(bytes)),
+                _ => None,
+            },
         }
     }
 
-    pub async fn new_auction(&mut self, title: String, duration : i64, initial_value: f32) {
+    pub async fn new_auction(&mut self, title: String, duration : i64, initial_value: Amount) {
         let auction = Auction::new(title,self.client.get_uid(), duration, initial_value);
         let auction_subscribers: Vec<NodeID> = Vec::new();
         let _ = self.client.annouce_auction(auction.to_gossip()).await;
@@ -55,10 +175,11 @@ impl AuctionPeer{
         }
     }
 
-    pub async  fn bid_auction(&mut self, index: i32,bid : f32)  {
+    pub async  fn bid_auction(&mut self, index: i32,bid : Amount)  {
         match self.known_auctions.get_mut(index) {
             Some(gossip) =>{ 
                 if let Ok(bidded_gossip) = gossip.bid(bid,self.client.get_uid()) {
+                self.client.record_bid(bidded_gossip.get_auction_id(), bid);
                 let _ = self.insert_subscribe(bidded_gossip.get_seller(), bidded_gossip.clone()).await;
                 }
             },
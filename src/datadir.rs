@@ -0,0 +1,21 @@
+use std::{env, fs, path::Path, sync::OnceLock};
+
+// overrides the directory the node keeps its keys, chain and settings in
+pub const DATA_DIR_VAR: &str = "AUCTIONS_DATA_DIR";
+const DEFAULT_DATA_DIR: &str = "config";
+
+/* Resolved once and created if it doesn't exist. Nodes sharing a machine need a directory each,
+   they would otherwise share their identity and write to the same block log. */
+pub fn data_dir() -> &'static str {
+    static DIR: OnceLock<String> = OnceLock::new();
+    DIR.get_or_init(|| {
+        let dir = env::var(DATA_DIR_VAR).ok().filter(|dir| !dir.is_empty()).unwrap_or_else(|| DEFAULT_DATA_DIR.to_owned());
+        fs::create_dir_all(&dir).unwrap_or_else(|e| panic!("Could not create the data directory {}: {}", dir, e));
+        dir
+    })
+}
+
+// path of a file in the data directory
+pub fn data_path(file: &str) -> String {
+    Path::new(data_dir()).join(file).to_string_lossy().into_owned()
+}
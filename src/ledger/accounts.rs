@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+
+use crate::p2p::key::NodeID;
+
+use super::{amount::Amount, block::{Block, Data}};
+
+/* Balances of every account, derived by replaying the chain from the genesis.
+   A settlement moves its amount from the buyer to the seller, then takes its fee from the seller;
+   settling an auction nobody bid on (the seller is also the buyer) only pays the fee. A coinbase
+   credits the miner, the genesis coinbases credit the initial allocations. */
+#[derive(Debug, Clone, Default)]
+pub struct Accounts {
+    balances: HashMap<NodeID, Amount>,
+}
+
+impl Accounts {
+    // replays stored blocks, which were validated when they were received
+    pub fn replay(blocks: &[Block]) -> Accounts {
+        let mut accounts = Accounts::default();
+        for block in blocks.iter() {
+            if let Err(e) = accounts.apply_block(block) {
+                println!("block with id: {} doesn't apply to the account state: {}", block.id, e);
+            }
+        }
+        accounts
+    }
+
+    pub fn from_balances(balances: Vec<(NodeID, Amount)>) -> Accounts {
+        Accounts {
+            balances: balances.into_iter().filter(|(_, balance)| *balance != Amount::ZERO).collect(),
+        }
+    }
+
+    // every non zero balance
+    pub fn balances(&self) -> Vec<(NodeID, Amount)> {
+        self.balances.iter().map(|(account, balance)| (*account, *balance)).collect()
+    }
+
+    pub fn balance(&self, account: NodeID) -> Amount {
+        self.balances.get(&account).copied().unwrap_or(Amount::ZERO)
+    }
+
+    pub fn apply_transaction(&mut self, data: &Data) -> Result<(), &'static str> {
+        if data.is_coinbase() {
+            return self.credit(data.get_seller(), data.get_amount());
+        }
+        // checked up front so a failing transaction leaves the balances untouched
+        let proceeds = if data.get_buyer() != data.get_seller() { data.get_amount() } else { Amount::ZERO };
+        if self.balance(data.get_seller()).checked_add(proceeds).is_some_and(|available| available < data.get_fee()) {
+            return Err("insufficient funds for the fee");
+        }
+        if data.get_buyer() != data.get_seller() {
+            self.transfer(data.get_buyer(), data.get_seller(), data.get_amount())?;
+        }
+        self.debit(data.get_seller(), data.get_fee())
+    }
+
+    // applies every transaction of a block, nothing is applied if one of them fails
+    pub fn apply_block(&mut self, block: &Block) -> Result<(), &'static str> {
+        let mut next = self.clone();
+        for data in block.transactions.iter() {
+            next.apply_transaction(data)?;
+        }
+        *self = next;
+        Ok(())
+    }
+
+    // undoes apply_block for the block at the tip, which can't fail since the block was applied
+    pub fn revert_block(&mut self, block: &Block) {
+        const NOT_APPLIED: &str = "reverting a block that wasn't applied";
+        for data in block.transactions.iter().rev() {
+            if data.is_coinbase() {
+                self.debit(data.get_seller(), data.get_amount()).expect(NOT_APPLIED);
+                continue;
+            }
+            self.credit(data.get_seller(), data.get_fee()).expect(NOT_APPLIED);
+            if data.get_buyer() != data.get_seller() {
+                self.transfer(data.get_seller(), data.get_buyer(), data.get_amount()).expect(NOT_APPLIED);
+            }
+        }
+    }
+
+    // callers work on a copy of the state, so a failed credit after the debit is simply discarded
+    fn transfer(&mut self, from: NodeID, to: NodeID, amount: Amount) -> Result<(), &'static str> {
+        self.debit(from, amount)?;
+        self.credit(to, amount)
+    }
+
+    fn credit(&mut self, account: NodeID, amount: Amount) -> Result<(), &'static str> {
+        let balance = self.balance(account).checked_add(amount).ok_or("balance overflow")?;
+        self.set(account, balance);
+        Ok(())
+    }
+
+    fn debit(&mut self, account: NodeID, amount: Amount) -> Result<(), &'static str> {
+        let balance = self.balance(account).checked_sub(amount).ok_or("insufficient funds")?;
+        self.set(account, balance);
+        Ok(())
+    }
+
+    fn set(&mut self, account: NodeID, balance: Amount) {
+        if balance == Amount::ZERO {
+            self.balances.remove(&account);
+        } else {
+            self.balances.insert(account, balance);
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use std::{fmt, str::FromStr};
+
+// amounts are counted in units of 10^-DECIMALS, and sent over the wire as that number of units
+pub const DECIMALS: u32 = 8;
+const UNIT: u64 = 10u64.pow(DECIMALS);
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    pub const fn from_units(units: u64) -> Amount {
+        Amount(units)
+    }
+
+    pub const fn from_whole(whole: u64) -> Amount {
+        Amount(whole * UNIT)
+    }
+
+    pub fn units(self) -> u64 {
+        self.0
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    pub fn to_be_bytes(self) -> [u8; 8] {
+        self.0.to_be_bytes()
+    }
+}
+
+// parses decimal notation like "12", "12.5" or "0.00000001"
+impl FromStr for Amount {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (whole, fraction) = s.split_once('.').unwrap_or((s, ""));
+        let digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+
+        if whole.is_empty() || !digits(whole) || !digits(fraction) || (s.contains('.') && fraction.is_empty()) {
+            return Err("invalid amount");
+        }
+        if fraction.len() > DECIMALS as usize {
+            return Err("too many decimal places");
+        }
+
+        let whole: u64 = whole.parse().map_err(|_| "amount too large")?;
+        let fraction: u64 = format!("{:0<width$}", fraction, width = DECIMALS as usize).parse().unwrap();
+        whole
+            .checked_mul(UNIT)
+            .and_then(|units| units.checked_add(fraction))
+            .map(Amount)
+            .ok_or("amount too large")
+    }
+}
+
+// decimal notation without trailing zeros
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (whole, fraction) = (self.0 / UNIT, self.0 % UNIT);
+        if fraction == 0 {
+            return write!(f, "{}", whole);
+        }
+        let fraction = format!("{:0width$}", fraction, width = DECIMALS as usize);
+        write!(f, "{}.{}", whole, fraction.trim_end_matches('0'))
+    }
+}
+
+impl fmt::Debug for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
@@ -0,0 +1,149 @@
+use std::{fs, io, sync::Arc};
+
+use openssl::pkey::PKey;
+use primitive_types::{H256, U256};
+
+use crate::p2p::key::{verify_signature, NodeValidator};
+
+use super::{block::{Ancestry, Block, BlockHeader}, consensus::Consensus, pool::Job};
+
+// PEM public keys of the signers, one after the other, the order defines the turns
+pub const AUTHORITIES: &str = "authorities";
+
+const PEM_END: &str = "-----END PUBLIC KEY-----";
+// a block sealed by the signer whose turn it is outweighs one sealed out of turn
+const IN_TURN_WORK: u64 = 2;
+const OUT_OF_TURN_WORK: u64 = 1;
+// seconds after its parent before a block may be sealed out of turn, the in-turn signer is late by then
+const OUT_OF_TURN_DELAY: i64 = 30;
+
+/* Proof of authority: blocks are sealed by a fixed set of signers taking turns, the signer of
+   block n is signers[n % len]. Others may seal out of turn once the block's timestamp is
+   OUT_OF_TURN_DELAY past its parent's, but a signer can't seal again until half of the set has
+   sealed after it. The seal is
+   [signer index (u32 BE)][signature of the block hash]. */
+#[derive(Debug)]
+pub struct ProofOfAuthority {
+    signers: Vec<Vec<u8>>,
+    // our position in the signer set and the keys we seal with, if we're one of the signers
+    local: Option<(u32, NodeValidator)>,
+}
+
+impl ProofOfAuthority {
+    pub fn new(signers: Vec<Vec<u8>>, validator: &NodeValidator) -> ProofOfAuthority {
+        let pub_key = validator.get_pubkey();
+        let local = signers
+            .iter()
+            .position(|s| *s == pub_key)
+            .map(|index| (index as u32, validator.clone()));
+
+        ProofOfAuthority { signers, local }
+    }
+
+    // reads the signer set, Ok(None) means the file doesn't exist and the chain isn't permissioned
+    pub fn load(path: &str, validator: &NodeValidator) -> io::Result<Option<ProofOfAuthority>> {
+        let pem = match fs::read_to_string(path) {
+            Ok(pem) => pem,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let mut signers = Vec::new();
+        for chunk in pem.split_inclusive(PEM_END).map(str::trim).filter(|c| !c.is_empty()) {
+            // keys are stored re-encoded so they compare equal to NodeValidator::get_pubkey
+            let key = PKey::public_key_from_pem(chunk.as_bytes())
+                .and_then(|k| k.public_key_to_pem())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            signers.push(key);
+        }
+        if signers.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "no signers in the authority set"));
+        }
+
+        Ok(Some(ProofOfAuthority::new(signers, validator)))
+    }
+
+    fn in_turn(&self, id: u64) -> u32 {
+        (id % self.signers.len() as u64) as u32
+    }
+
+    // the in-turn signer may seal right away, the others have to wait for it to be late
+    fn early(&self, signer: u32, id: u64, timestamp: i64, parent: &Block) -> bool {
+        signer != self.in_turn(id) && timestamp < parent.timestamp.saturating_add(OUT_OF_TURN_DELAY)
+    }
+
+    // whether the signer sealed one of the last len/2 blocks up to `parent`
+    fn recently_sealed(&self, signer: u32, parent: &Block, chain: &dyn Ancestry) -> bool {
+        let limit = self.signers.len() as u64 / 2;
+        let mut block = Some(parent);
+        for _ in 0..limit {
+            match block {
+                Some(b) if b.id > 0 => {
+                    if signer_index(&b.seal) == Some(signer) {
+                        return true;
+                    }
+                    block = chain.ancestor(b, b.id - 1);
+                },
+                _ => break,
+            }
+        }
+        false
+    }
+}
+
+impl Consensus for ProofOfAuthority {
+    fn prepare(&self, header: &mut BlockHeader, parent: &Block, chain: &dyn Ancestry) -> Result<(), &'static str> {
+        let (index, _) = self.local.as_ref().ok_or("not an authority")?;
+        if self.recently_sealed(*index, parent, chain) {
+            return Err("sealed too recently, waiting for the other signers");
+        }
+        if self.early(*index, header.id, header.timestamp, parent) {
+            return Err("not our turn, waiting for the in-turn signer");
+        }
+
+        header.target = parent.target;
+        header.nonce = 0;
+        Ok(())
+    }
+
+    // signing is instant, so there's nothing to cancel
+    fn seal(&self, header: &mut BlockHeader, _job: &Arc<Job>) -> Option<(H256, Vec<u8>)> {
+        let (index, validator) = self.local.as_ref()?;
+        let hash = header.hash();
+
+        let mut seal = index.to_be_bytes().to_vec();
+        seal.extend(validator.sign(hash.as_bytes()));
+        Some((hash, seal))
+    }
+
+    fn verify(&self, block: &Block, parent: &Block, chain: &dyn Ancestry) -> Result<(), &'static str> {
+        let index = signer_index(&block.seal).ok_or("missing block seal")?;
+        let signer = self.signers.get(index as usize).ok_or("sealed by an unknown signer")?;
+
+        if block.target != parent.target || block.nonce != 0 {
+            Err("unexpected proof of work fields")
+        } else if !verify_signature(signer, block.hash.as_bytes(), &block.seal[4..]) {
+            Err("invalid block seal")
+        } else if self.recently_sealed(index, parent, chain) {
+            Err("signer sealed too recently")
+        } else if self.early(index, block.id, block.timestamp, parent) {
+            Err("sealed out of turn too early")
+        } else {
+            Ok(())
+        }
+    }
+
+    fn work(&self, block: &Block) -> U256 {
+        match signer_index(&block.seal) {
+            Some(index) if index == self.in_turn(block.id) => U256::from(IN_TURN_WORK),
+            Some(_) => U256::from(OUT_OF_TURN_WORK),
+            // the genesis block isn't sealed
+            None => U256::zero(),
+        }
+    }
+}
+
+fn signer_index(seal: &[u8]) -> Option<u32> {
+    let bytes = seal.get(0..4)?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
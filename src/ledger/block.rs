@@ -1,10 +1,59 @@
+use std::collections::{HashMap, HashSet};
+
 use chrono::Utc;
-use openssl::sha::Sha256;
-use primitive_types::H256;
-use rand:: Rng;
+use openssl::sha::{sha256, Sha256};
+use primitive_types::{H256, U256};
+
+use crate::{p2p::key::{node_id, verify_signature, NodeID, NodeValidator}, auctions::auction::AuctionGossip};
 
-use crate::{p2p::key::{NodeID, leading_zeros}, auctions::auction::AuctionGossip};
-const DIFFICULTY: u32 = 8;
+use super::{
+    accounts::Accounts,
+    amount::Amount,
+    consensus::Consensus,
+    difficulty::target_bytes,
+    genesis::genesis,
+    index::{TxIndex, TxLocation},
+    merkle::{merkle_proof, merkle_root, MerkleProof},
+    reward::reward_schedule,
+    snapshot::Snapshot,
+    timestamp::{check_timestamp, median_time_past},
+};
+// limit on the summed size of a block's transactions, see Data::size
+pub const MAX_BLOCK_SIZE: usize = 128 * 1024;
+// buyer, seller, amount, fee and auction id
+const TERMS_SIZE: usize = 32 + 32 + 8 + 8 + 32;
+const MAX_ORPHANS: usize = 64;
+
+// everything the block's seal commits to, the block's hash is the hash of its header
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct BlockHeader {
+    pub id: u64,
+    pub prev_hash: H256,
+    pub merkle_root: H256,
+    pub timestamp: i64,
+    pub target: U256,
+    pub nonce: u64,
+}
+
+impl BlockHeader {
+    // canonical serialization: id, prev_hash, merkle_root, timestamp, target, nonce (integers big endian)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(8 + 32 + 32 + 8 + 32 + 8);
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        bytes.extend_from_slice(self.prev_hash.as_bytes());
+        bytes.extend_from_slice(self.merkle_root.as_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&target_bytes(self.target));
+        bytes.extend_from_slice(&self.nonce.to_be_bytes());
+        bytes
+    }
+
+    pub fn hash(&self) -> H256 {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.to_bytes());
+        H256::from(hasher.finish())
+    }
+}
 
 #[derive(Debug,Clone)]
 pub struct Block {
@@ -13,72 +62,231 @@ pub struct Block {
    pub prev_hash: H256,
    pub hash: H256,
    pub timestamp: i64,
-   pub data: Data   
+   pub target: U256,
+   pub merkle_root: H256,
+   // consensus specific proof that isn't part of the header, empty under proof of work
+   pub seal: Vec<u8>,
+   pub transactions: Vec<Data>,
 }
 
 impl Block {
-    pub fn new(id: u64,nonce : u64, prev_hash: H256, hash: H256, timestamp: i64,data: Data) -> Block {
+    pub fn new(header: BlockHeader, hash: H256, seal: Vec<u8>, transactions: Vec<Data>) -> Block {
         Block {
-            id,
-            nonce,
-            prev_hash,
+            id: header.id,
+            nonce: header.nonce,
+            prev_hash: header.prev_hash,
             hash,
-            timestamp,
-            data,
+            timestamp: header.timestamp,
+            target: header.target,
+            merkle_root: header.merkle_root,
+            seal,
+            transactions,
+        }
+    }
+
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            id: self.id,
+            prev_hash: self.prev_hash,
+            merkle_root: self.merkle_root,
+            timestamp: self.timestamp,
+            target: self.target,
+            nonce: self.nonce,
         }
     }
 
-    pub fn mine_block(id: u64, previous_hash: H256, data: Data) -> Block {
-        let (nonce, hash) = proof_of_work(previous_hash, &data);
-        let timestamp = Utc::now().timestamp();
-        Block{ 
-            id,
-            nonce, 
-            prev_hash : previous_hash,                                                          // VER OS TIPOS DA HASH (STRING OU VEC<U8>)
-            hash, 
-            timestamp, 
-            data
+    // the block without its transactions, which is what headers-first sync exchanges
+    pub fn header_only(&self) -> Block {
+        Block::new(self.header(), self.hash, self.seal.clone(), Vec::new())
+    }
+
+    pub fn compute_merkle_root(&self) -> H256 {
+        merkle_root(&tx_hashes(&self.transactions))
+    }
+
+    /* A list repeating its last transactions can have the same merkle root as the list without
+       them, since odd levels duplicate their last node. Such a block is invalid, but shares its
+       hash with the valid one. */
+    pub fn has_duplicate_transactions(&self) -> bool {
+        let mut seen = HashSet::new();
+        !self.transactions.iter().all(|t| seen.insert(t.hash()))
+    }
+
+    // proof that the transaction with the given hash is committed to by this block's merkle root
+    pub fn merkle_proof(&self, tx_hash: H256) -> Option<MerkleProof> {
+        let hashes = tx_hashes(&self.transactions);
+        let index = hashes.iter().position(|h| *h == tx_hash)?;
+        merkle_proof(&hashes, index)
+    }
+}
+
+pub fn tx_hashes(transactions: &[Data]) -> Vec<H256> {
+    transactions.iter().map(|t| t.hash()).collect()
+}
+
+// None if the sum overflows
+pub fn total_fees(transactions: &[Data]) -> Option<Amount> {
+    transactions.iter().try_fold(Amount::ZERO, |total, t| total.checked_add(t.get_fee()))
+}
+
+// a party's PEM public key and its signature of the settlement terms
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct TxSignature {
+    pub pub_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl TxSignature {
+    fn sign(validator: &NodeValidator, terms: H256) -> TxSignature {
+        TxSignature {
+            pub_key: validator.get_pubkey(),
+            signature: validator.sign(terms.as_bytes()),
         }
     }
-    
+
+    // the key must hash to the signer's NodeID, so nobody has to be trusted to provide it
+    fn verify(&self, signer: NodeID, terms: H256) -> bool {
+        node_id(&self.pub_key) == signer && verify_signature(&self.pub_key, terms.as_bytes(), &self.signature)
+    }
 }
 
 #[derive(Debug,Clone)]
 pub struct Data {
     buyer: NodeID,
     seller: NodeID,
-    amount: f32,
+    amount: Amount,
+    // paid by the seller to the miner, on top of the settlement
+    fee: Amount,
     auction_id: H256,
+    seller_signature: TxSignature,
+    buyer_signature: TxSignature,
 }
 
 impl Data {
-    pub fn new(buyer: NodeID, seller: NodeID, amount: f32, auction_id: H256) -> Data {
+    pub fn new(buyer: NodeID, seller: NodeID, amount: Amount, auction_id: H256) -> Data {
         Data {
             buyer,
             seller,
             amount,
+            fee: Amount::ZERO,
             auction_id,
+            seller_signature: TxSignature::default(),
+            buyer_signature: TxSignature::default(),
         }
     }
 
-    pub fn hash(&self) -> H256 {
+    /* First transaction of every block, paying the block reward to the miner. It's a settlement
+       from the null node, which can't sign anything, on an "auction" named after the block's height
+       so every coinbase has a different hash. */
+    pub fn coinbase(miner: NodeID, amount: Amount, height: u64) -> Data {
+        Data::new(null_node(), miner, amount, H256::from_low_u64_be(height))
+    }
+
+    // first transaction of the genesis, a coinbase paying nothing whose auction id commits to the chain id
+    pub fn chain_id(chain_id: &str) -> Data {
+        Data::new(null_node(), null_node(), Amount::ZERO, H256::from(sha256(chain_id.as_bytes())))
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.buyer == null_node()
+    }
+
+    pub fn with_fee(mut self, fee: Amount) -> Data {
+        self.fee = fee;
+        self
+    }
+
+    pub fn with_signatures(mut self, seller_signature: TxSignature, buyer_signature: TxSignature) -> Data {
+        self.seller_signature = seller_signature;
+        self.buyer_signature = buyer_signature;
+        self
+    }
+
+    // hash of the settlement terms, which is what the seller and the buyer sign
+    pub fn terms_hash(&self) -> H256 {
         let mut hasher = Sha256::new();
         hasher.update(self.buyer.as_bytes());
         hasher.update(self.seller.as_bytes());
         hasher.update(&self.amount.to_be_bytes());
+        hasher.update(&self.fee.to_be_bytes());
         hasher.update(self.auction_id.as_bytes());
 
         H256::from(hasher.finish())
     }
 
+    // the transaction's id, it commits to the signatures as well as to the terms
+    pub fn hash(&self) -> H256 {
+        let mut hasher = Sha256::new();
+        hasher.update(self.terms_hash().as_bytes());
+        for signature in [&self.seller_signature, &self.buyer_signature] {
+            hasher.update(&(signature.pub_key.len() as u32).to_be_bytes());
+            hasher.update(&signature.pub_key);
+            hasher.update(&(signature.signature.len() as u32).to_be_bytes());
+            hasher.update(&signature.signature);
+        }
+
+        H256::from(hasher.finish())
+    }
+
+    // bytes the transaction takes up in a block: its terms, then both keys and signatures with their lengths
+    pub fn size(&self) -> usize {
+        let signatures: usize = [&self.seller_signature, &self.buyer_signature]
+            .iter()
+            .map(|s| 4 + s.pub_key.len() + 4 + s.signature.len())
+            .sum();
+        TERMS_SIZE + signatures
+    }
+
+    pub fn sign_as_seller(&mut self, validator: &NodeValidator) -> Result<(), &'static str> {
+        if validator.get_nodeid() != self.seller {
+            return Err("only the seller can sign a settlement");
+        }
+        self.seller_signature = TxSignature::sign(validator, self.terms_hash());
+        Ok(())
+    }
+
+    pub fn countersign(&mut self, validator: &NodeValidator) -> Result<(), &'static str> {
+        if validator.get_nodeid() != self.buyer {
+            return Err("only the buyer can counter-sign a settlement");
+        }
+        if !self.seller_signature.verify(self.seller, self.terms_hash()) {
+            return Err("invalid seller signature");
+        }
+        self.buyer_signature = TxSignature::sign(validator, self.terms_hash());
+        Ok(())
+    }
+
+    pub fn verify_signatures(&self) -> Result<(), &'static str> {
+        let terms = self.terms_hash();
+        if !self.seller_signature.verify(self.seller, terms) {
+            Err("invalid seller signature")
+        } else if !self.buyer_signature.verify(self.buyer, terms) {
+            Err("invalid buyer signature")
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn get_seller_signature(&self) -> &TxSignature {
+        &self.seller_signature
+    }
+
+    pub fn get_buyer_signature(&self) -> &TxSignature {
+        &self.buyer_signature
+    }
+
     pub fn get_auction_id(&self) -> H256 {
         self.auction_id
     }
 
-    pub fn get_amount(&self) -> f32 {
+    pub fn get_amount(&self) -> Amount {
         self.amount
     }
 
+    pub fn get_fee(&self) -> Amount {
+        self.fee
+    }
+
     pub fn get_seller(&self) -> NodeID {
         self.seller
     }
@@ -88,152 +296,899 @@ impl Data {
     }
 
     pub fn from_auction(auction: AuctionGossip) -> Data {
-        Data {
-            // should be buyer, just testing blockchain
-            buyer: auction.get_seller(),
-            seller: auction.get_seller(),
-            amount: auction.get_price(),
-            auction_id:auction.get_auction_id() ,
+        Data::new(auction.get_buyer(), auction.get_seller(), auction.get_price(), auction.get_auction_id())
+    }
+}
+
+fn null_node() -> NodeID {
+    NodeID::from_h256(H256::zero())
+}
+
+// a main chain transaction and the merkle path to its block's root, checkable with the headers alone
+#[derive(Debug, Clone)]
+pub struct InclusionProof {
+    pub height: u64,
+    pub block_hash: H256,
+    pub transaction: Data,
+    pub proof: MerkleProof,
+}
+
+// blocks removed from and added to the main chain by a single update, disconnected from the old tip down
+#[derive(Debug,Clone,Default)]
+pub struct Reorg {
+    pub disconnected: Vec<Block>,
+    pub connected: Vec<Block>,
+}
+
+impl Reorg {
+    pub fn is_empty(&self) -> bool {
+        self.disconnected.is_empty() && self.connected.is_empty()
+    }
+
+    // combines two consecutive updates into one, blocks connected and later disconnected cancel out
+    fn merge(&mut self, other: Reorg) {
+        for block in other.disconnected {
+            match self.connected.iter().position(|b| b.hash == block.hash) {
+                Some(index) => {
+                    self.connected.remove(index);
+                },
+                None => self.disconnected.push(block),
+            }
         }
+        self.connected.extend(other.connected);
     }
 }
 
 #[derive(Debug,Clone)]
 pub struct Chain {
-   pub blocks: Vec<Block>
+   pub blocks: Vec<Block>,
+   // valid blocks that aren't part of the main chain, by hash
+   side: HashMap<H256, Block>,
+   // blocks whose parent is still unknown, by the parent's hash
+   orphans: HashMap<H256, Vec<Block>>,
+   // balances at the tip of the main chain
+   accounts: Accounts,
+   // cumulative work of the main chain up to each block, see count_work
+   work: Vec<U256>,
+   // where the main chain's transactions are, by auction and by account
+   index: TxIndex,
+   // height of the oldest main chain block that still has its body
+   pruned: u64,
 }
 
 impl Chain {
     pub fn new() -> Self {
-        let null_node = NodeID::from_h256(H256::zero());
-        let data = Data::new(null_node,null_node,0.0,H256::zero());
-        let (nonce,cur_hash) = proof_of_work(H256::zero(),&data);
-
-        let genesis = Block {
-            id : 0,
-            nonce,
-            prev_hash: H256::zero(),
-            hash : cur_hash,
-            timestamp : Utc::now().timestamp(),
-            data,
+        Self::from_blocks(vec![genesis().clone()])
+    }
+
+    pub fn from_blocks(blocks: Vec<Block>) -> Self {
+        Self {
+            accounts: Accounts::replay(&blocks),
+            index: TxIndex::build(&blocks),
+            blocks,
+            side: HashMap::new(),
+            orphans: HashMap::new(),
+            work: Vec::new(),
+            pruned: 0,
+        }
+    }
+
+    /* Rebuilds the chain from a snapshot and the blocks from the genesis onwards, which have to
+       include the snapshot's block. Bodies up to the snapshot are dropped and later blocks are
+       replayed on top of its state, which is trusted like stored blocks are. */
+    pub fn from_snapshot(snapshot: Snapshot, blocks: &[Block]) -> Result<Self, &'static str> {
+        let height = snapshot.height as usize;
+        if blocks.get(height).map(|b| b.hash) != Some(snapshot.hash) {
+            return Err("snapshot doesn't match the blocks");
+        }
+
+        let mut chain = Self {
+            blocks: blocks[..=height].iter().map(|b| b.header_only()).collect(),
+            side: HashMap::new(),
+            orphans: HashMap::new(),
+            accounts: Accounts::from_balances(snapshot.balances),
+            index: TxIndex::from_archive(snapshot.settlements),
+            work: Vec::new(),
+            pruned: snapshot.height + 1,
         };
+        for block in blocks[height + 1..].iter() {
+            if let Err(e) = chain.accounts.apply_block(block) {
+                println!("block with id: {} doesn't apply to the account state: {}", block.id, e);
+            }
+            chain.index.connect(block);
+            chain.blocks.push(block.clone());
+        }
+        Ok(chain)
+    }
 
-        Self {blocks: vec![genesis]}
+    // checks a header chain from its genesis, before bootstrapping from a snapshot
+    pub fn validate_header_chain(headers: &[Block], consensus: &dyn Consensus) -> Result<(), &'static str> {
+        if headers.first().is_some_and(|h| h.hash != genesis().hash) {
+            return Err("headers start from a different genesis");
+        }
+        match headers.split_first() {
+            Some((genesis, rest)) if !rest.is_empty() => {
+                Chain::scratch(vec![genesis.clone()]).validate_headers(rest, consensus).map(|_| ())
+            },
+            Some(_) => Ok(()),
+            None => Err("no headers"),
+        }
     }
 
-    pub fn get_chain(&self) -> Chain {
-        self.clone()
+    // a chain that's only used to look up ancestors for the consensus rules, without balances or indexes
+    fn scratch(blocks: Vec<Block>) -> Self {
+        Self {
+            blocks,
+            side: HashMap::new(),
+            orphans: HashMap::new(),
+            accounts: Accounts::default(),
+            index: TxIndex::default(),
+            work: Vec::new(),
+            pruned: 0,
+        }
     }
 
-    pub fn replace(&mut self, chain: Vec<Block>) {
-        self.blocks = chain;
+    pub fn tip(&self) -> &Block {
+        self.blocks.last().unwrap()
     }
 
-    pub fn mine(&mut self, data: Data) -> Block {
-        let prev_block = self.blocks.last().unwrap();
-        let block = Block::mine_block(prev_block.id, prev_block.hash,data);
-        self.blocks.push(block.clone());
-        return block;
+    pub fn accounts(&self) -> &Accounts {
+        &self.accounts
     }
 
-    pub fn add_block(&mut self, block: Block) -> bool{
-        let last_block = self.blocks.last().unwrap();
-        if self.validate_block(&block, last_block) {
-            self.blocks.push(block);
-            true
-        } else {
-            false
-        }
+    pub fn pruned_height(&self) -> u64 {
+        self.pruned
     }
 
-    fn validate_block(&self,block: &Block, last_block: &Block) -> bool {
-        if block.prev_hash != last_block.hash {
-            println!("block with id: {} has invalid prev_hash", block.id);
-            return false;
+    pub fn archived_settlements(&self) -> impl Iterator<Item = &(u64, Data)> {
+        self.index.archive()
+    }
+
+    // the state after the block at `height`, the bodies of the blocks after it are needed to compute it
+    pub fn snapshot(&self, height: u64) -> Result<Snapshot, &'static str> {
+        if height > self.tip().id {
+            return Err("height beyond the tip");
+        } else if height + 1 < self.pruned {
+            return Err("blocks after that height were pruned");
         }
 
-        else if block.id != (last_block.id + 1) {
-            println!("block with id: {} has invalid id", block.id);
-            return false;
+        let mut accounts = self.accounts.clone();
+        for block in self.blocks[height as usize + 1..].iter().rev() {
+            accounts.revert_block(block);
         }
+        Ok(Snapshot {
+            height,
+            hash: self.blocks[height as usize].hash,
+            balances: accounts.balances(),
+            settlements: self.index.settlements_at(height, |l| self.locate(l).clone()),
+            headers: self.blocks[..=height as usize].iter().map(|b| b.header_only()).collect(),
+        })
+    }
 
-        else if test_proof_of_work(block) {
-            println!("block with id: {} is a malicious block (wrong nonce)", block.id);
+    // drops the bodies of the blocks more than `keep` blocks below the tip, returns whether any was dropped
+    pub fn prune(&mut self, keep: u64) -> bool {
+        let target = (self.tip().id + 1).saturating_sub(keep);
+        if target <= self.pruned {
             return false;
         }
+        for height in self.pruned..target {
+            let block = &mut self.blocks[height as usize];
+            self.index.archive_block(block);
+            *block = block.header_only();
+        }
+        self.pruned = target;
         true
     }
-/* UNUSED
-    pub fn validate_chain(&self,chain: &Chain) -> bool { 
-        for i in 1..chain.blocks.len() {                                               //verificar se começa no 1
-            let first = chain.blocks.get(i-1).expect("has to exist");
-            let second = chain.blocks.get(i).expect("has to exist");
-            if self.validate_block(second, first) != true {
-                return false;
-            } 
+
+    /* Switches the main chain to `branch`, which builds on the main chain block at height `fork`.
+       Fails without changes if its transactions overspend. */
+    pub fn replace(&mut self, fork: usize, branch: Vec<Block>, consensus: &dyn Consensus) -> Result<Reorg, &'static str> {
+        // blocks the branch shares with the main chain stay where they are
+        let shared = self.blocks[fork + 1..].iter().zip(branch.iter()).take_while(|(a, b)| a.hash == b.hash).count();
+        let fork = fork + 1 + shared;
+        let branch = &branch[shared..];
+        // disconnected blocks need their bodies to be reverted
+        if (fork as u64) < self.pruned {
+            return Err("can't reorg below the pruned height");
+        }
+        let mut accounts = self.accounts.clone();
+        for block in self.blocks[fork..].iter().rev() {
+            accounts.revert_block(block);
+        }
+        for block in branch.iter() {
+            accounts.apply_block(block)?;
+        }
+
+        self.accounts = accounts;
+        let disconnected: Vec<Block> = self.blocks.drain(fork..).rev().collect();
+        for block in disconnected.iter() {
+            self.index.disconnect(block);
+            self.side.insert(block.hash, block.clone());
+        }
+        self.blocks.extend(branch.iter().cloned());
+        for block in self.blocks[fork..].iter() {
+            self.index.connect(block);
+            self.side.remove(&block.hash);
+        }
+        self.work.truncate(fork);
+        self.count_work(consensus);
+
+        Ok(Reorg {
+            disconnected,
+            connected: self.blocks[fork..].to_vec(),
+        })
+    }
+
+    // header of the next block on the tip, ready to be sealed, `transactions` start with the block's coinbase
+    pub fn block_template(&self, transactions: &[Data], consensus: &dyn Consensus) -> Result<BlockHeader, &'static str> {
+        let prev_block = self.tip();
+        let mut header = BlockHeader {
+            id: prev_block.id + 1,
+            prev_hash: prev_block.hash,
+            merkle_root: merkle_root(&tx_hashes(transactions)),
+            // a clock behind the recent blocks still produces a valid timestamp
+            timestamp: Utc::now().timestamp().max(median_time_past(prev_block, self) + 1),
+            target: prev_block.target,
+            nonce: 0,
+        };
+        consensus.prepare(&mut header, prev_block, self)?;
+        Ok(header)
+    }
+
+    // finds the settlement of an auction and proves its inclusion against the block's merkle root
+    pub fn prove_settlement(&self, auction_id: H256) -> Option<InclusionProof> {
+        let location = self.index.settlement(&auction_id)?;
+        let block = &self.blocks[location.height as usize];
+        let data = &block.transactions[location.index];
+        let proof = block.merkle_proof(data.hash())?;
+        Some(InclusionProof {
+            height: block.id,
+            block_hash: block.hash,
+            transaction: data.clone(),
+            proof,
+        })
+    }
+
+    // checks a settlement proof against our main chain headers, returns the block's confirmations
+    pub fn verify_inclusion(&self, proof: &InclusionProof) -> Result<u64, &'static str> {
+        let block = self.blocks.get(proof.height as usize).filter(|b| b.hash == proof.block_hash);
+        let block = block.ok_or("block isn't in the main chain")?;
+        if proof.transaction.is_coinbase() {
+            Err("not a settlement")
+        } else if !proof.proof.verify(block.merkle_root, proof.transaction.hash()) {
+            Err("invalid merkle proof")
+        } else {
+            Ok(self.tip().id - block.id + 1)
         }
-        true
     }
 
-    pub fn choose_chain(&self, local: Chain, remote: Chain) -> Chain {
-        let is_local_valid = self.validate_chain(&local);       // não preciso de testar a local
-        let is_remote_valid = self.validate_chain(&remote);
+    // latest settlement of an auction on the main chain and the height of its block
+    pub fn find_settlement(&self, auction_id: &H256) -> Option<(u64, &Data)> {
+        match self.index.settlement(auction_id) {
+            Some(location) => Some((location.height, self.locate(location))),
+            None => self.index.archived(auction_id).map(|(height, data)| (*height, data)),
+        }
+    }
+
+    // transactions paying or paid by the account on the main chain, oldest first
+    pub fn account_transactions(&self, account: &NodeID) -> Vec<(u64, &Data)> {
+        self.index.account(account).iter().map(|l| (l.height, self.locate(*l))).collect()
+    }
+
+    // main chain blocks are stored by height
+    fn locate(&self, location: TxLocation) -> &Data {
+        &self.blocks[location.height as usize].transactions[location.index]
+    }
+
+    // main chain hashes from the tip back to the genesis: the last 10 blocks, then doubling the step
+    pub fn locator(&self) -> Vec<H256> {
+        let mut locator = Vec::new();
+        let mut height = self.tip().id;
+        let mut step = 1;
+        while height > 0 {
+            locator.push(self.blocks[height as usize].hash);
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+        locator.push(self.blocks[0].hash);
+        locator
+    }
+
+    // up to `max` main chain headers after the first locator hash we know, from the genesis if none
+    pub fn headers_after(&self, locator: &[H256], max: usize) -> Vec<Block> {
+        let start = locator.iter().find_map(|hash| self.position(hash)).unwrap_or(0);
+        self.blocks[start + 1..].iter().take(max).map(|b| b.header_only()).collect()
+    }
+
+    /* Checks header-only blocks received during a sync: they have to form a branch starting at a
+       main chain block and follow the consensus rules, their bodies are validated once downloaded.
+       Returns the cumulative work of the chain they lead to. */
+    pub fn validate_headers(&self, headers: &[Block], consensus: &dyn Consensus) -> Result<U256, &'static str> {
+        let first = headers.first().ok_or("no headers")?;
+        let fork = self.position(&first.prev_hash).ok_or("headers don't connect to the main chain")?;
+        let mut branch = HeaderBranch { chain: self, headers: HashMap::new() };
+        let mut parent = &self.blocks[fork];
+        let mut work = self.work_at(fork, consensus);
+
+        for header in headers.iter() {
+            if header.prev_hash != parent.hash {
+                return Err("invalid prev_hash");
+            } else if header.id != parent.id + 1 {
+                return Err("invalid id");
+            } else if header.header().hash() != header.hash {
+                return Err("hash doesn't match the block header");
+            }
+            check_timestamp(header, parent, &branch)?;
+            consensus.verify(header, parent, &branch)?;
+            branch.headers.insert(header.hash, header);
+            work += consensus.work(header);
+            parent = header;
+        }
+
+        Ok(work)
+    }
 
-        match (is_local_valid,is_remote_valid) {
-            (true, true) => {
-                if local.blocks.len() >= remote.blocks.len() {
-                    local
-                } else {
-                    remote
-                }
+    /* Light clients only follow the headers: validated headers leading to more work than the main
+       chain replace it from the fork. Header-only blocks don't change the balances or the index. */
+    pub fn connect_headers(&mut self, headers: Vec<Block>, consensus: &dyn Consensus) -> Result<Reorg, &'static str> {
+        let work = self.validate_headers(&headers, consensus)?;
+        if work <= self.work(consensus) {
+            return Ok(Reorg::default());
+        }
+        let fork = self.position(&headers[0].prev_hash).ok_or("headers don't connect to the main chain")?;
+        self.replace(fork, headers, consensus)
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.side.contains_key(hash) || self.position(hash).is_some()
+    }
+
+    /* Adds a block to the tree: it either extends the main chain, extends a side branch (switching
+       the main chain to it if it now has more work) or waits in the orphan pool for its parent.
+       Orphans waiting on the new block are connected right after it. */
+    pub fn add_block(&mut self, block: Block, consensus: &dyn Consensus) -> Result<Reorg, &'static str> {
+        // a mutated copy of a block has its hash, it can't be kept as an orphan in place of the real one
+        if block.has_duplicate_transactions() {
+            return Err("duplicate transaction");
+        }
+        let orphaned = self.orphans.get(&block.prev_hash).is_some_and(|o| o.iter().any(|b| b.hash == block.hash));
+        if orphaned || self.contains(&block.hash) {
+            return Err("block already known");
+        }
+
+        let mut reorg = Reorg::default();
+        let mut pending = vec![block];
+        let mut first = true;
+
+        while let Some(block) = pending.pop() {
+            let hash = block.hash;
+            match self.connect(block, consensus) {
+                Ok(Some(r)) => reorg.merge(r),
+                Ok(None) => (),
+                // errors from orphans don't invalidate the block that released them
+                Err(e) if first => return Err(e),
+                Err(_) => continue,
+            }
+            first = false;
+            if let Some(children) = self.orphans.remove(&hash) {
+                pending.extend(children);
+            }
+        }
+
+        Ok(reorg)
+    }
+
+    // Ok(None) means the block was stored without changing the main chain
+    fn connect(&mut self, block: Block, consensus: &dyn Consensus) -> Result<Option<Reorg>, &'static str> {
+        if block.prev_hash == self.tip().hash {
+            self.extend(block.clone(), consensus)?;
+            return Ok(Some(Reorg { disconnected: Vec::new(), connected: vec![block] }));
+        }
+
+        let parent = match self.get_block(&block.prev_hash) {
+            Some(parent) => parent,
+            None => {
+                self.insert_orphan(block);
+                return Ok(None);
+            },
+        };
+        validate_block(&block, parent, consensus, self)?;
+        let hash = block.hash;
+        self.side.insert(hash, block);
+
+        let (fork, work) = match self.branch_work(hash, consensus) {
+            Ok(branch) => branch,
+            Err(e) => {
+                self.side.remove(&hash);
+                return Err(e);
+            },
+        };
+        if work <= self.work(consensus) {
+            return Ok(None);
+        }
+
+        println!("switching main chain to the branch forking at block {}", self.blocks[fork].id);
+        let branch = self.branch(hash);
+        let hashes: Vec<H256> = branch.iter().map(|b| b.hash).collect();
+        match self.replace(fork, branch, consensus) {
+            Ok(reorg) => Ok(Some(reorg)),
+            Err(e) => {
+                // the branch is invalid as a whole, so none of its blocks can become part of the main chain
+                self.drop_side(hashes);
+                Err(e)
             },
-            (true, false) => local,
-            (false, true) => remote,
-            (false, false) => local, // this is not supposed to happen
         }
-     
     }
-*/
+
+    /* Drops side blocks along with the side blocks and orphans building on them, which couldn't
+       become part of the main chain either. A side block is never left without its parent. */
+    fn drop_side(&mut self, hashes: Vec<H256>) {
+        let mut dropped = hashes;
+        while let Some(hash) = dropped.pop() {
+            self.side.remove(&hash);
+            self.orphans.remove(&hash);
+            dropped.extend(self.side.values().filter(|b| b.prev_hash == hash).map(|b| b.hash));
+        }
+    }
+
+    // appends a block that builds on the tip
+    fn extend(&mut self, block: Block, consensus: &dyn Consensus) -> Result<(), &'static str> {
+        validate_block(&block, self.tip(), consensus, self)?;
+        self.accounts.apply_block(&block)?;
+        self.index.connect(&block);
+        self.blocks.push(block);
+        self.count_work(consensus);
+        Ok(())
+    }
+
+    fn insert_orphan(&mut self, block: Block) {
+        let count: usize = self.orphans.values().map(|o| o.len()).sum();
+        if count >= MAX_ORPHANS {
+            println!("orphan pool is full, dropping block with id: {}", block.id);
+            return;
+        }
+        println!("block with id: {} is an orphan, waiting for its parent", block.id);
+        self.orphans.entry(block.prev_hash).or_default().push(block);
+    }
+
+    fn position(&self, hash: &H256) -> Option<usize> {
+        self.blocks.iter().rposition(|b| &b.hash == hash)
+    }
+
+    // a block with its transactions, pruned main chain blocks only have their header
+    pub fn body(&self, hash: &H256) -> Option<&Block> {
+        self.get_block(hash).filter(|b| b.id >= self.pruned || self.side.contains_key(&b.hash))
+    }
+
+    // a main chain or side block
+    pub fn get_block(&self, hash: &H256) -> Option<&Block> {
+        match self.side.get(hash) {
+            Some(block) => Some(block),
+            None => self.position(hash).map(|i| &self.blocks[i]),
+        }
+    }
+
+    // whether the auction was settled in `parent` or a block before it, on whatever branch it is
+    fn settled(&self, auction_id: &H256, parent: &Block) -> bool {
+        let mut block = parent;
+        while let Some(side) = self.side.get(&block.hash) {
+            if side.transactions.iter().any(|t| !t.is_coinbase() && t.get_auction_id() == *auction_id) {
+                return true;
+            }
+            match self.get_block(&side.prev_hash) {
+                Some(prev) => block = prev,
+                None => break,
+            }
+        }
+        self.index.settled(auction_id, block.id)
+    }
+
+    // the side blocks from the main chain up to `tip`
+    fn branch(&self, tip: H256) -> Vec<Block> {
+        let mut branch = Vec::new();
+        let mut hash = tip;
+        while let Some(block) = self.side.get(&hash) {
+            branch.push(block.clone());
+            hash = block.prev_hash;
+        }
+        branch.reverse();
+        branch
+    }
+
+    /* Walks back from a side block to the main chain, returning the fork height and the work of the
+       chain ending at `tip`. Fails if the branch doesn't lead back to the main chain. */
+    fn branch_work(&self, tip: H256, consensus: &dyn Consensus) -> Result<(usize, U256), &'static str> {
+        let mut work = U256::zero();
+        let mut hash = tip;
+        while let Some(block) = self.side.get(&hash) {
+            work += consensus.work(block);
+            hash = block.prev_hash;
+        }
+        let fork = self.position(&hash).ok_or("unknown ancestor")?;
+        Ok((fork, self.work_at(fork, consensus) + work))
+    }
+
+    pub fn work(&self, consensus: &dyn Consensus) -> U256 {
+        self.work_at(self.blocks.len() - 1, consensus)
+    }
+
+    // cumulative work of the main chain up to `height`, blocks that weren't counted yet are added up
+    fn work_at(&self, height: usize, consensus: &dyn Consensus) -> U256 {
+        let counted = self.work.len().min(height + 1);
+        let base = counted.checked_sub(1).map_or(U256::zero(), |i| self.work[i]);
+        base + chain_work(&self.blocks[counted..=height], consensus)
+    }
+
+    /* Counts the work of the main chain blocks that weren't counted yet. The chain is built without
+       its consensus rules, the node calls this once they're known, then it's kept up to date. */
+    pub fn count_work(&mut self, consensus: &dyn Consensus) {
+        while self.work.len() < self.blocks.len() {
+            let previous = self.work.last().copied().unwrap_or_default();
+            self.work.push(previous + consensus.work(&self.blocks[self.work.len()]));
+        }
+    }
 }
 
+/* Blocks by hash, on the main chain or on a branch. The consensus rules only look at a block's
+   ancestors through this, so headers can be checked on top of the chain without copying it. */
+pub trait Ancestry {
+    fn get_block(&self, hash: &H256) -> Option<&Block>;
 
-pub fn proof_of_work(previous_hash: H256, data: &Data) -> (u64, H256) {
-    let mut nonce_ex: u64;
+    // the block at the given height on the branch ending at `block`, which can be a side branch
+    fn ancestor<'a>(&'a self, mut block: &'a Block, height: u64) -> Option<&'a Block> {
+        while block.id > height {
+            block = self.get_block(&block.prev_hash)?;
+        }
+        Some(block).filter(|b| b.id == height)
+    }
+}
 
-    // Hash of our new block
-    let mut hasher = Sha256::new();
-    let data_hash = data.hash();
-    hasher.update(previous_hash.as_bytes());
-    hasher.update(data_hash.as_bytes());
-    let new_block_hash = H256::from(hasher.finish()); 
-    // Descover the right nonce
-    loop {
-        let mut rng = rand::thread_rng();
-        nonce_ex = rng.gen();
-        let nonce_bytes = nonce_ex.clone().to_be_bytes();
-        let mut hasher = Sha256::new();
+impl Ancestry for Chain {
+    fn get_block(&self, hash: &H256) -> Option<&Block> {
+        Chain::get_block(self, hash)
+    }
+}
 
-        hasher.update(new_block_hash.as_bytes());
-        hasher.update(&nonce_bytes);   
+// headers being validated, on top of the main chain block they fork from
+struct HeaderBranch<'a> {
+    chain: &'a Chain,
+    headers: HashMap<H256, &'a Block>,
+}
+
+impl Ancestry for HeaderBranch<'_> {
+    fn get_block(&self, hash: &H256) -> Option<&Block> {
+        self.headers.get(hash).copied().or_else(|| self.chain.get_block(hash))
+    }
+}
 
-        if leading_zeros(&hasher.finish()) >= DIFFICULTY {    
-            return (nonce_ex, new_block_hash);
+fn validate_block(block: &Block, last_block: &Block, consensus: &dyn Consensus, chain: &Chain) -> Result<(), &'static str> {
+    if block.prev_hash != last_block.hash {
+        Err("invalid prev_hash")
+    } else if block.id != (last_block.id + 1) {
+        Err("invalid id")
+    } else if block.transactions.iter().map(|t| t.size()).sum::<usize>() > MAX_BLOCK_SIZE {
+        Err("block too large")
+    } else if block.has_duplicate_transactions() {
+        Err("duplicate transaction")
+    } else if block.merkle_root != block.compute_merkle_root() {
+        Err("invalid merkle root")
+    } else if block.header().hash() != block.hash {
+        Err("hash doesn't match the block header")
+    } else {
+        check_timestamp(block, last_block, chain)?;
+        consensus.verify(block, last_block, chain)?;
+        validate_coinbase(block)?;
+        validate_settlements(block, last_block, chain)?;
+        block.transactions[1..].iter().try_for_each(|t| t.verify_signatures())
+    }
+}
+
+/* The buyer's signature only covers the settlement terms, so anyone could include a confirmed
+   settlement again and charge the buyer twice. An auction is settled at most once per branch. */
+fn validate_settlements(block: &Block, parent: &Block, chain: &Chain) -> Result<(), &'static str> {
+    let mut auctions = HashSet::new();
+    for data in block.transactions.iter().filter(|t| !t.is_coinbase()) {
+        if !auctions.insert(data.get_auction_id()) {
+            return Err("auction settled twice in the block");
+        } else if chain.settled(&data.get_auction_id(), parent) {
+            return Err("auction already settled");
         }
-    }      
+    }
+    Ok(())
 }
 
-fn test_proof_of_work(block: &Block) -> bool {
-    if  !(block.data.hash() == block.hash) {
-        return false;
-    }  
+// the coinbase carries no signatures, it's valid as long as it pays exactly the subsidy and the block's fees
+fn validate_coinbase(block: &Block) -> Result<(), &'static str> {
+    match block.transactions.split_first() {
+        Some((coinbase, rest)) if coinbase.is_coinbase() => {
+            let reward = total_fees(rest).and_then(|fees| fees.checked_add(reward_schedule().subsidy(block.id)));
+            if rest.iter().any(|t| t.is_coinbase()) {
+                Err("more than one coinbase")
+            } else if coinbase.get_auction_id() != H256::from_low_u64_be(block.id) {
+                Err("coinbase doesn't match the block height")
+            } else if Some(coinbase.get_amount()) != reward {
+                Err("invalid coinbase amount")
+            } else {
+                Ok(())
+            }
+        },
+        _ => Err("missing coinbase"),
+    }
+}
+
+pub fn chain_work(blocks: &[Block], consensus: &dyn Consensus) -> U256 {
+    blocks.iter().fold(U256::zero(), |acc, b| acc + consensus.work(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use openssl::pkey::PKey;
+
+    use crate::{
+        ledger::{consensus::{proof_of_work, ProofOfWork}, difficulty::pow_limit, pool::Job},
+        p2p::scheme::Scheme,
+    };
+
+    // accepts any block and weighs them all the same, so branches can be built without mining
+    #[derive(Debug)]
+    struct AnyBlock;
+
+    impl Consensus for AnyBlock {
+        fn prepare(&self, _header: &mut BlockHeader, _parent: &Block, _chain: &dyn Ancestry) -> Result<(), &'static str> {
+            Ok(())
+        }
+
+        fn seal(&self, header: &mut BlockHeader, _job: &Arc<Job>) -> Option<(H256, Vec<u8>)> {
+            Some((header.hash(), Vec::new()))
+        }
+
+        fn verify(&self, _block: &Block, _parent: &Block, _chain: &dyn Ancestry) -> Result<(), &'static str> {
+            Ok(())
+        }
+
+        fn work(&self, _block: &Block) -> U256 {
+            U256::one()
+        }
+    }
+
+    // a mined block with only its coinbase, on top of the genesis
+    fn mined_block(chain: &Chain, consensus: &dyn Consensus) -> Block {
+        let coinbase = Data::coinbase(NodeID::from_h256(H256::repeat_byte(1)), reward_schedule().subsidy(1), 1);
+        let mut header = chain.block_template(std::slice::from_ref(&coinbase), consensus).unwrap();
+        let hash = proof_of_work(&mut header);
+        Block::new(header, hash, Vec::new(), vec![coinbase])
+    }
+
+    type Tamper = fn(&mut Block);
+
+    // an unsealed block on `parent`, only good for the checks that don't involve the consensus
+    fn child(parent: &Block, transactions: Vec<Data>) -> Block {
+        child_by(parent, 1, transactions)
+    }
+
+    // blocks with the same parent and transactions differ by the account their coinbase pays
+    fn child_by(parent: &Block, miner: u8, transactions: Vec<Data>) -> Block {
+        let mut transactions = transactions;
+        let coinbase = Data::coinbase(NodeID::from_h256(H256::repeat_byte(miner)), reward_schedule().subsidy(parent.id + 1), parent.id + 1);
+        transactions.insert(0, coinbase);
+        let header = BlockHeader {
+            id: parent.id + 1,
+            prev_hash: parent.hash,
+            merkle_root: merkle_root(&tx_hashes(&transactions)),
+            timestamp: parent.timestamp + 1,
+            target: parent.target,
+            nonce: 0,
+        };
+        Block::new(header.clone(), header.hash(), Vec::new(), transactions)
+    }
+
+    fn settlement(auction: u64, amount: u64) -> Data {
+        let (buyer, seller) = (NodeID::from_h256(H256::repeat_byte(2)), NodeID::from_h256(H256::repeat_byte(3)));
+        Data::new(buyer, seller, Amount::from_whole(amount), H256::from_low_u64_be(auction))
+    }
+
+    // a settlement signed by a new buyer and seller, the buyer has no funds to pay for it
+    fn signed_settlement(auction: u64, amount: u64) -> Data {
+        let (buyer, seller) = (PKey::generate_ed25519().unwrap(), PKey::generate_ed25519().unwrap());
+        let (buyer_pem, seller_pem) = (buyer.public_key_to_pem().unwrap(), seller.public_key_to_pem().unwrap());
+        let data = Data::new(node_id(&buyer_pem), node_id(&seller_pem), Amount::from_whole(amount), H256::from_low_u64_be(auction));
+        let terms = data.terms_hash();
+        let sign = |key: &PKey<_>, pub_key: Vec<u8>| TxSignature {
+            pub_key,
+            signature: Scheme::Ed25519.sign(key, terms.as_bytes()).unwrap(),
+        };
+        let (seller_signature, buyer_signature) = (sign(&seller, seller_pem), sign(&buyer, buyer_pem));
+        data.with_signatures(seller_signature, buyer_signature)
+    }
+
+    // every side block's parent is a side block or a main chain block
+    fn side_is_connected(chain: &Chain) -> bool {
+        chain.side.values().all(|b| chain.get_block(&b.prev_hash).is_some())
+    }
+
+    // a chain of `length` blocks after the genesis
+    fn main_chain(length: usize) -> Chain {
+        let mut chain = Chain::new();
+        for _ in 0..length {
+            let block = child(chain.tip(), Vec::new());
+            chain.add_block(block, &AnyBlock).unwrap();
+        }
+        chain
+    }
+
+    // changes a header field, keeping the hash the block was mined with
+    fn tampered(block: &Block, tamper: impl Fn(&mut Block)) -> Block {
+        let mut block = block.clone();
+        tamper(&mut block);
+        block
+    }
+
+    #[test]
+    fn mined_block_is_valid() {
+        let (chain, consensus) = (Chain::new(), ProofOfWork::new());
+        let block = mined_block(&chain, &consensus);
+        assert_eq!(validate_block(&block, chain.tip(), &consensus, &chain), Ok(()));
+    }
+
+    #[test]
+    fn header_hash_covers_every_field() {
+        let (chain, consensus) = (Chain::new(), ProofOfWork::new());
+        let header = mined_block(&chain, &consensus).header();
+        let tampers: [fn(&mut BlockHeader); 6] = [
+            |h| h.id += 1,
+            |h| h.prev_hash = H256::repeat_byte(2),
+            |h| h.merkle_root = H256::repeat_byte(3),
+            |h| h.timestamp += 1,
+            |h| h.target = h.target - 1,
+            |h| h.nonce = h.nonce.wrapping_add(1),
+        ];
+        for tamper in tampers.iter() {
+            let mut other = header.clone();
+            tamper(&mut other);
+            assert_ne!(other.hash(), header.hash());
+        }
+    }
+
+    #[test]
+    fn tampered_fields_are_rejected() {
+        let (chain, consensus) = (Chain::new(), ProofOfWork::new());
+        let block = mined_block(&chain, &consensus);
+        let parent = chain.tip();
+        let cases: [(Tamper, &str); 7] = [
+            (|b| b.id += 1, "invalid id"),
+            (|b| b.prev_hash = H256::repeat_byte(2), "invalid prev_hash"),
+            (|b| b.merkle_root = H256::repeat_byte(3), "invalid merkle root"),
+            (|b| b.timestamp += 1, "hash doesn't match the block header"),
+            (|b| b.target = b.target - 1, "hash doesn't match the block header"),
+            (|b| b.nonce = b.nonce.wrapping_add(1), "hash doesn't match the block header"),
+            (|b| b.hash = H256::zero(), "hash doesn't match the block header"),
+        ];
+        for (tamper, error) in cases.iter() {
+            assert_eq!(validate_block(&tampered(&block, tamper), parent, &consensus, &chain), Err(*error));
+        }
+    }
+
+    #[test]
+    fn rehashed_fields_are_rejected() {
+        let (chain, consensus) = (Chain::new(), ProofOfWork::new());
+        let block = mined_block(&chain, &consensus);
+        let parent = chain.tip();
+
+        // a matching hash doesn't help once the consensus rules see the tampered target
+        let mut easier = tampered(&block, |b| b.target = pow_limit());
+        easier.hash = easier.header().hash();
+        assert_eq!(validate_block(&easier, parent, &consensus, &chain), Err("target doesn't follow the retarget rule"));
+
+        // nor does a timestamp that isn't after the genesis
+        let mut header = tampered(&block, |b| b.timestamp = parent.timestamp).header();
+        let hash = proof_of_work(&mut header);
+        let early = Block::new(header, hash, Vec::new(), block.transactions.clone());
+        assert_eq!(
+            validate_block(&early, parent, &consensus, &chain),
+            Err("timestamp isn't after the median time of the previous blocks")
+        );
+    }
+
+    #[test]
+    fn settlements_are_unique_in_a_block() {
+        let chain = Chain::new();
+        let block = child(chain.tip(), vec![settlement(7, 1), settlement(7, 2)]);
+        assert_eq!(validate_settlements(&block, chain.tip(), &chain), Err("auction settled twice in the block"));
+        let block = child(chain.tip(), vec![settlement(7, 1), settlement(8, 1)]);
+        assert_eq!(validate_settlements(&block, chain.tip(), &chain), Ok(()));
+    }
+
+    #[test]
+    fn settlements_are_unique_on_a_branch() {
+        let genesis = genesis().clone();
+        let settled = child(&genesis, vec![settlement(7, 1)]);
+        let mut chain = Chain::from_blocks(vec![genesis.clone(), settled.clone()]);
+
+        // the main chain settled the auction, the same terms can't be included again
+        let replay = child(chain.tip(), vec![settlement(7, 1)]);
+        assert_eq!(validate_settlements(&replay, chain.tip(), &chain), Err("auction already settled"));
+
+        // a branch forking before the settlement can settle it
+        let side = child(&genesis, vec![settlement(8, 1)]);
+        chain.side.insert(side.hash, side.clone());
+        let other = child(&side, vec![settlement(7, 1)]);
+        assert_eq!(validate_settlements(&other, &side, &chain), Ok(()));
+
+        // but only once
+        chain.side.insert(other.hash, other.clone());
+        let replay = child(&other, vec![settlement(7, 1)]);
+        assert_eq!(validate_settlements(&replay, &other, &chain), Err("auction already settled"));
+        let replay = child(&other, vec![settlement(8, 1)]);
+        assert_eq!(validate_settlements(&replay, &other, &chain), Err("auction already settled"));
+    }
+
+    #[test]
+    fn overspending_branch_drops_its_descendants() {
+        let mut chain = main_chain(3);
+        let genesis = chain.blocks[0].clone();
+        let s1 = child_by(&genesis, 2, Vec::new());
+        let s2 = child_by(&s1, 2, Vec::new());
+        let s2b = child_by(&s1, 3, Vec::new());
+        let s3 = child_by(&s2, 2, vec![signed_settlement(7, 1)]);
+        let s4 = child_by(&s3, 2, Vec::new());
+        for block in [&s1, &s2, &s2b, &s3] {
+            assert!(chain.add_block(block.clone(), &AnyBlock).unwrap().is_empty());
+        }
+
+        // s4 outweighs the main chain, but the buyer in s3 can't pay
+        assert_eq!(chain.add_block(s4, &AnyBlock).err(), Some("insufficient funds"));
+        assert!(!chain.contains(&s2b.hash));
+        assert!(side_is_connected(&chain));
+
+        // a block on the dropped s2b waits for its parent like any orphan
+        let s3b = child_by(&s2b, 3, Vec::new());
+        assert!(chain.add_block(s3b, &AnyBlock).unwrap().is_empty());
+        assert!(side_is_connected(&chain));
+    }
+
+    #[test]
+    fn branch_without_its_ancestor_is_rejected() {
+        let mut chain = main_chain(2);
+        let genesis = chain.blocks[0].clone();
+        let s1 = child_by(&genesis, 2, Vec::new());
+        let s2 = child_by(&s1, 2, Vec::new());
+        for block in [&s1, &s2] {
+            chain.add_block(block.clone(), &AnyBlock).unwrap();
+        }
+        let work = chain.work(&AnyBlock);
+
+        // s2 lost its parent, the work of a branch through it can't be counted
+        chain.side.remove(&s1.hash);
+        let s3 = child_by(&s2, 2, Vec::new());
+        assert_eq!(chain.add_block(s3.clone(), &AnyBlock).err(), Some("unknown ancestor"));
+        assert!(!chain.contains(&s3.hash));
+        assert_eq!(chain.work(&AnyBlock), work);
+    }
+
+    #[test]
+    fn reorg_below_the_pruned_height_drops_the_branch() {
+        let mut chain = main_chain(3);
+        assert!(chain.prune(2));
+        let genesis = chain.blocks[0].clone();
+        let s1 = child_by(&genesis, 2, Vec::new());
+        let s2 = child_by(&s1, 2, Vec::new());
+        let s2b = child_by(&s1, 3, Vec::new());
+        let s3 = child_by(&s2, 2, Vec::new());
+        let s4 = child_by(&s3, 2, Vec::new());
+        for block in [&s1, &s2, &s2b, &s3] {
+            assert!(chain.add_block(block.clone(), &AnyBlock).unwrap().is_empty());
+        }
 
-    let nonce_bytes = block.nonce.clone().to_be_bytes();
-    let mut hasher = Sha256::new();
-    hasher.update(&block.hash.as_bytes());
-    hasher.update(&nonce_bytes);
+        assert_eq!(chain.add_block(s4, &AnyBlock).err(), Some("can't reorg below the pruned height"));
+        assert!(chain.side.is_empty());
 
-    if leading_zeros(&hasher.finish()) >= DIFFICULTY {
-        return true;
+        let s3b = child_by(&s2b, 3, Vec::new());
+        assert!(chain.add_block(s3b, &AnyBlock).unwrap().is_empty());
+        assert!(side_is_connected(&chain));
     }
-    false
 }
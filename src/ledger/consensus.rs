@@ -0,0 +1,117 @@
+use std::{fmt, sync::Arc};
+
+use primitive_types::{H256, U256};
+
+use crate::{datadir::data_path, p2p::key::NodeValidator};
+
+use super::{
+    authority::{ProofOfAuthority, AUTHORITIES},
+    block::{Ancestry, Block, BlockHeader},
+    difficulty::{meets_target, next_target, retarget_start, work},
+    pool::{Job, WorkerPool},
+};
+
+/* Rules that decide who may extend the chain and how much a block weighs in the fork choice.
+   Everything else about a block (linkage, merkle root, header hash) is checked by the chain. */
+pub trait Consensus: Send + Sync + fmt::Debug {
+    // fills the fields of a header built on `parent` that the consensus rules derive from the chain
+    fn prepare(&self, header: &mut BlockHeader, parent: &Block, chain: &dyn Ancestry) -> Result<(), &'static str>;
+
+    // seals a prepared header, returning the block's hash and seal, None if the job was cancelled
+    fn seal(&self, header: &mut BlockHeader, job: &Arc<Job>) -> Option<(H256, Vec<u8>)>;
+
+    // checks the consensus fields of a block whose parent is `parent`
+    fn verify(&self, block: &Block, parent: &Block, chain: &dyn Ancestry) -> Result<(), &'static str>;
+
+    // weight of a block in the fork choice, the main chain is the one with the most cumulative work
+    fn work(&self, block: &Block) -> U256;
+}
+
+impl<C: Consensus + ?Sized> Consensus for Box<C> {
+    fn prepare(&self, header: &mut BlockHeader, parent: &Block, chain: &dyn Ancestry) -> Result<(), &'static str> {
+        (**self).prepare(header, parent, chain)
+    }
+
+    fn seal(&self, header: &mut BlockHeader, job: &Arc<Job>) -> Option<(H256, Vec<u8>)> {
+        (**self).seal(header, job)
+    }
+
+    fn verify(&self, block: &Block, parent: &Block, chain: &dyn Ancestry) -> Result<(), &'static str> {
+        (**self).verify(block, parent, chain)
+    }
+
+    fn work(&self, block: &Block) -> U256 {
+        (**self).work(block)
+    }
+}
+
+// the chain is permissioned when an authority set is configured, otherwise anyone can mine
+pub fn load_consensus(validator: &NodeValidator) -> Box<dyn Consensus> {
+    match ProofOfAuthority::load(&data_path(AUTHORITIES), validator).expect("Could not read the authority set") {
+        Some(poa) => {
+            println!("Using proof of authority");
+            Box::new(poa)
+        },
+        None => Box::new(ProofOfWork::new()),
+    }
+}
+
+#[derive(Debug)]
+pub struct ProofOfWork {
+    pool: WorkerPool,
+}
+
+impl ProofOfWork {
+    pub fn new() -> ProofOfWork {
+        let pool = WorkerPool::new();
+        println!("Mining on {} threads", pool.threads());
+        ProofOfWork { pool }
+    }
+
+    // target required for the block after `parent`, which can be on the main chain or on a side branch
+    fn next_target(&self, parent: &Block, chain: &dyn Ancestry) -> U256 {
+        let first = retarget_start(parent).and_then(|height| chain.ancestor(parent, height));
+        next_target(parent, first)
+    }
+}
+
+impl Consensus for ProofOfWork {
+    fn prepare(&self, header: &mut BlockHeader, parent: &Block, chain: &dyn Ancestry) -> Result<(), &'static str> {
+        header.target = self.next_target(parent, chain);
+        Ok(())
+    }
+
+    fn seal(&self, header: &mut BlockHeader, job: &Arc<Job>) -> Option<(H256, Vec<u8>)> {
+        let (hash, nonce) = self.pool.search(header, job)?;
+        header.nonce = nonce;
+        Some((hash, Vec::new()))
+    }
+
+    fn verify(&self, block: &Block, parent: &Block, chain: &dyn Ancestry) -> Result<(), &'static str> {
+        if block.target != self.next_target(parent, chain) {
+            Err("target doesn't follow the retarget rule")
+        } else if !meets_target(block.hash.as_bytes(), block.target) {
+            Err("malicious block (wrong nonce)")
+        } else if !block.seal.is_empty() {
+            Err("unexpected block seal")
+        } else {
+            Ok(())
+        }
+    }
+
+    // every block contributes the expected number of hashes needed to solve its puzzle
+    fn work(&self, block: &Block) -> U256 {
+        work(block.target)
+    }
+}
+
+// single threaded search from the header's nonce for one that puts its hash under its target, returning that hash
+pub fn proof_of_work(header: &mut BlockHeader) -> H256 {
+    loop {
+        let hash = header.hash();
+        if meets_target(hash.as_bytes(), header.target) {
+            return hash;
+        }
+        header.nonce = header.nonce.wrapping_add(1);
+    }
+}
@@ -0,0 +1,60 @@
+use primitive_types::U256;
+
+use super::block::Block;
+
+// the target is retargeted every RETARGET_INTERVAL blocks to keep blocks TARGET_BLOCK_TIME seconds apart
+pub const RETARGET_INTERVAL: u64 = 10;
+pub const TARGET_BLOCK_TIME: i64 = 30;
+// a single retarget can't change the target by more than this factor
+const MAX_ADJUSTMENT: i64 = 4;
+
+// easiest target allowed, a hash needs at least 4 leading zero bits
+pub fn pow_limit() -> U256 {
+    U256::MAX >> 4
+}
+
+// target of the genesis block, 8 leading zero bits
+pub fn initial_target() -> U256 {
+    U256::MAX >> 8
+}
+
+pub fn meets_target(hash: &[u8], target: U256) -> bool {
+    U256::from_big_endian(hash) <= target
+}
+
+// expected number of hashes needed to find a block with the given target
+pub fn work(target: U256) -> U256 {
+    (!target / (target + 1)) + 1
+}
+
+// height of the block that opens the retarget window closed by `parent`, if the next block retargets
+pub fn retarget_start(parent: &Block) -> Option<u64> {
+    let height = parent.id + 1;
+    if height.is_multiple_of(RETARGET_INTERVAL) {
+        Some(height - RETARGET_INTERVAL)
+    } else {
+        None
+    }
+}
+
+/* Target required for the block after `parent`. `first` is the block at retarget_start(parent),
+   the target is scaled by how long the window actually took against how long it should have taken.
+   From `first` to `parent` there are RETARGET_INTERVAL - 1 block intervals. */
+pub fn next_target(parent: &Block, first: Option<&Block>) -> U256 {
+    let first = match (retarget_start(parent), first) {
+        (Some(_), Some(first)) => first,
+        _ => return parent.target,
+    };
+
+    let expected = TARGET_BLOCK_TIME * (RETARGET_INTERVAL - 1) as i64;
+    let actual = (parent.timestamp - first.timestamp).clamp(expected / MAX_ADJUSTMENT, expected * MAX_ADJUSTMENT);
+    let target = parent.target / U256::from(expected) * U256::from(actual);
+
+    target.min(pow_limit())
+}
+
+pub fn target_bytes(target: U256) -> Vec<u8> {
+    let mut bytes = [0u8; 32];
+    target.to_big_endian(&mut bytes);
+    bytes.to_vec()
+}
@@ -0,0 +1,97 @@
+use std::{fs, sync::OnceLock};
+
+use primitive_types::{H256, U256};
+
+use crate::{datadir::data_path, p2p::key::NodeID};
+
+use super::{amount::Amount, block::{tx_hashes, Block, BlockHeader, Data}, consensus::proof_of_work, difficulty::initial_target, merkle::merkle_root};
+
+/* One "<key> <value>" per line, keys left out take the built-in values below:
+     chain_id <name>
+     timestamp <unix seconds>
+     target <64 hex digits>
+     allocation <NodeID in hex> <amount>    (once per funded account) */
+pub const GENESIS: &str = "genesis";
+
+const CHAIN_ID: &str = "auctions";
+const TIMESTAMP: i64 = 1_650_000_000;
+
+/* What the genesis block is built from, every node of a network needs the same spec to agree on
+   the genesis hash. The chain id and the initial allocations are committed to by the genesis
+   transactions, so networks with a different spec never share a block. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GenesisSpec {
+    pub chain_id: String,
+    pub timestamp: i64,
+    pub target: U256,
+    pub allocations: Vec<(NodeID, Amount)>,
+}
+
+impl Default for GenesisSpec {
+    fn default() -> Self {
+        GenesisSpec {
+            chain_id: CHAIN_ID.to_owned(),
+            timestamp: TIMESTAMP,
+            target: initial_target(),
+            allocations: Vec::new(),
+        }
+    }
+}
+
+impl GenesisSpec {
+    pub fn parse(contents: &str) -> Result<GenesisSpec, &'static str> {
+        let mut spec = GenesisSpec::default();
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next(), fields.next(), fields.next()) {
+                (Some("chain_id"), Some(chain_id), None, None) => spec.chain_id = chain_id.to_owned(),
+                (Some("timestamp"), Some(timestamp), None, None) => {
+                    spec.timestamp = timestamp.parse().map_err(|_| "invalid genesis timestamp")?;
+                },
+                (Some("target"), Some(target), None, None) => {
+                    let bytes = hex::decode(target).ok().filter(|bytes| bytes.len() == 32).ok_or("invalid genesis target")?;
+                    spec.target = U256::from_big_endian(&bytes);
+                },
+                (Some("allocation"), Some(account), Some(amount), None) => {
+                    let account = hex::decode(account).ok().filter(|id| id.len() == 32).ok_or("invalid allocation account")?;
+                    let amount = amount.parse::<Amount>().map_err(|_| "invalid allocation amount")?;
+                    spec.allocations.push((NodeID::from_h256(H256::from_slice(&account)), amount));
+                },
+                _ => return Err("invalid genesis spec line"),
+            }
+        }
+        Ok(spec)
+    }
+
+    /* The chain id marker comes first, then a coinbase crediting every allocation. The nonce is
+       searched from 0, so the proof of work is the same on every node. */
+    pub fn block(&self) -> Block {
+        let mut transactions = vec![Data::chain_id(&self.chain_id)];
+        transactions.extend(self.allocations.iter().map(|(account, amount)| Data::coinbase(*account, *amount, 0)));
+        let mut header = BlockHeader {
+            id: 0,
+            prev_hash: H256::zero(),
+            merkle_root: merkle_root(&tx_hashes(&transactions)),
+            timestamp: self.timestamp,
+            target: self.target,
+            nonce: 0,
+        };
+        let hash = proof_of_work(&mut header);
+        Block::new(header, hash, Vec::new(), transactions)
+    }
+}
+
+// read once, an invalid spec stops the node rather than starting it on another network
+pub fn genesis_spec() -> &'static GenesisSpec {
+    static SPEC: OnceLock<GenesisSpec> = OnceLock::new();
+    SPEC.get_or_init(|| match fs::read_to_string(data_path(GENESIS)) {
+        Ok(contents) => GenesisSpec::parse(&contents).unwrap_or_else(|e| panic!("{} in {}", e, data_path(GENESIS))),
+        Err(_) => GenesisSpec::default(),
+    })
+}
+
+// the block every chain of this network starts from
+pub fn genesis() -> &'static Block {
+    static BLOCK: OnceLock<Block> = OnceLock::new();
+    BLOCK.get_or_init(|| genesis_spec().block())
+}
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+
+use primitive_types::H256;
+
+use crate::p2p::key::NodeID;
+
+use super::block::{Block, Data};
+
+// where a transaction sits in the main chain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxLocation {
+    pub height: u64,
+    // position in the block's transactions
+    pub index: usize,
+}
+
+/* Secondary indexes over the main chain, kept in step with it the same way as the balances:
+   blocks are connected in order and disconnected from the tip down. Every list is in chain
+   order, so disconnecting a block only pops from the back. Settlements of pruned blocks are
+   archived with their data, the account index forgets them. */
+#[derive(Debug, Clone, Default)]
+pub struct TxIndex {
+    // settlements of every auction, only one unless blocks stored by an earlier version repeat it
+    by_auction: HashMap<H256, Vec<TxLocation>>,
+    // transactions where the account is the buyer or the seller, and coinbases paying it
+    by_account: HashMap<NodeID, Vec<TxLocation>>,
+    // latest settlement of every auction settled in a pruned block, with the block's height
+    archived: HashMap<H256, (u64, Data)>,
+}
+
+impl TxIndex {
+    pub fn build(blocks: &[Block]) -> TxIndex {
+        let mut index = TxIndex::default();
+        for block in blocks.iter() {
+            index.connect(block);
+        }
+        index
+    }
+
+    // the archive of a snapshot, blocks after it are connected on top
+    pub fn from_archive(settlements: Vec<(u64, Data)>) -> TxIndex {
+        TxIndex {
+            archived: settlements.into_iter().map(|(height, data)| (data.get_auction_id(), (height, data))).collect(),
+            ..TxIndex::default()
+        }
+    }
+
+    // latest settlement of the auction in a block that wasn't pruned
+    pub fn settlement(&self, auction_id: &H256) -> Option<TxLocation> {
+        self.by_auction.get(auction_id)?.last().copied()
+    }
+
+    // whether the auction was settled on the main chain at or below `height`
+    pub fn settled(&self, auction_id: &H256, height: u64) -> bool {
+        self.archived.contains_key(auction_id)
+            || self.by_auction.get(auction_id).is_some_and(|locations| locations.iter().any(|l| l.height <= height))
+    }
+
+    pub fn archived(&self, auction_id: &H256) -> Option<&(u64, Data)> {
+        self.archived.get(auction_id)
+    }
+
+    pub fn archive(&self) -> impl Iterator<Item = &(u64, Data)> {
+        self.archived.values()
+    }
+
+    // latest settlement of every auction up to the given height, `locate` looks up indexed transactions
+    pub fn settlements_at<F: Fn(TxLocation) -> Data>(&self, height: u64, locate: F) -> Vec<(u64, Data)> {
+        let mut settlements: HashMap<H256, (u64, Data)> = self.archived.clone();
+        for (auction_id, locations) in self.by_auction.iter() {
+            if let Some(location) = locations.iter().rev().find(|l| l.height <= height) {
+                settlements.insert(*auction_id, (location.height, locate(*location)));
+            }
+        }
+        settlements.into_values().collect()
+    }
+
+    // moves the settlements of the oldest block with a body to the archive, before that body is pruned
+    pub fn archive_block(&mut self, block: &Block) {
+        for data in block.transactions.iter() {
+            drop_height(&mut self.by_account, data.get_seller(), block.id);
+            if data.is_coinbase() {
+                continue;
+            }
+            drop_height(&mut self.by_account, data.get_buyer(), block.id);
+            drop_height(&mut self.by_auction, data.get_auction_id(), block.id);
+            self.archived.insert(data.get_auction_id(), (block.id, data.clone()));
+        }
+    }
+
+    pub fn account(&self, account: &NodeID) -> &[TxLocation] {
+        self.by_account.get(account).map_or(&[], |locations| locations.as_slice())
+    }
+
+    pub fn connect(&mut self, block: &Block) {
+        for (index, data) in block.transactions.iter().enumerate() {
+            let location = TxLocation { height: block.id, index };
+            self.by_account.entry(data.get_seller()).or_default().push(location);
+            if data.is_coinbase() {
+                continue;
+            }
+            self.by_auction.entry(data.get_auction_id()).or_default().push(location);
+            if data.get_buyer() != data.get_seller() {
+                self.by_account.entry(data.get_buyer()).or_default().push(location);
+            }
+        }
+    }
+
+    pub fn disconnect(&mut self, block: &Block) {
+        for data in block.transactions.iter() {
+            pop_height(&mut self.by_account, data.get_seller(), block.id);
+            if !data.is_coinbase() {
+                pop_height(&mut self.by_auction, data.get_auction_id(), block.id);
+                pop_height(&mut self.by_account, data.get_buyer(), block.id);
+            }
+        }
+    }
+}
+
+// drops the entries of the oldest indexed block for the key, which are the first ones
+fn drop_height<K: std::hash::Hash + Eq>(map: &mut HashMap<K, Vec<TxLocation>>, key: K, height: u64) {
+    if let Some(locations) = map.get_mut(&key) {
+        let count = locations.iter().take_while(|l| l.height == height).count();
+        locations.drain(..count);
+        if locations.is_empty() {
+            map.remove(&key);
+        }
+    }
+}
+
+// drops the entries of the block at `height` for the key, which are the last ones
+fn pop_height<K: std::hash::Hash + Eq>(map: &mut HashMap<K, Vec<TxLocation>>, key: K, height: u64) {
+    if let Some(locations) = map.get_mut(&key) {
+        while locations.last().is_some_and(|l| l.height == height) {
+            locations.pop();
+        }
+        if locations.is_empty() {
+            map.remove(&key);
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::Utc;
+use primitive_types::H256;
+
+use crate::p2p::key::NodeID;
+
+use super::{accounts::Accounts, amount::Amount, block::{Block, Chain, Data, Reorg}};
+
+// pending transactions older than this (in seconds) are dropped
+pub const MEMPOOL_EXPIRY: i64 = 60 * 60;
+const MAX_MEMPOOL: usize = 4096;
+
+#[derive(Debug, Clone)]
+struct Entry {
+    data: Data,
+    size: usize,
+    arrival: i64,
+    // breaks ties between transactions that arrived in the same second
+    sequence: u64,
+}
+
+/* Transactions waiting to be mined, keyed by hash. It mirrors the main chain so it can refuse
+   settlements that are already confirmed: transactions leave the pool when a block including
+   them is connected and come back when that block is disconnected by a reorg. */
+#[derive(Debug, Default)]
+pub struct Mempool {
+    entries: HashMap<H256, Entry>,
+    // pending settlement of every auction in the pool
+    pending: HashMap<H256, H256>,
+    confirmed: HashSet<H256>,
+    // number of confirmed settlements of every auction
+    settled: HashMap<H256, usize>,
+    sequence: u64,
+}
+
+impl Mempool {
+    pub fn from_chain(chain: &Chain) -> Mempool {
+        let mut mempool = Mempool::default();
+        for (_, data) in chain.archived_settlements() {
+            mempool.confirm(data);
+        }
+        // pruned blocks don't have transactions anymore
+        for block in chain.blocks.iter() {
+            mempool.connect(block);
+        }
+        mempool
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /* `accounts` holds the confirmed balances, which have to cover what the buyer and the seller
+       already spend in the pool too. Only the proceeds of the transaction itself count towards
+       the seller's fee, those of other pending settlements might never be confirmed. */
+    pub fn insert(&mut self, data: Data, accounts: &Accounts) -> Result<(), &'static str> {
+        let now = Utc::now().timestamp();
+        self.evict_expired(now);
+        let hash = data.hash();
+        let affordable = |account: NodeID| {
+            let spent = self.pending_spend(account).and_then(|s| s.checked_add(spent_by(&data, account)?));
+            let available = accounts.balance(account).checked_add(received_by(&data, account));
+            matches!((spent, available), (Some(spent), Some(available)) if spent <= available)
+        };
+
+        if data.is_coinbase() {
+            Err("coinbase transactions can't be relayed")
+        } else if self.entries.contains_key(&hash) {
+            Err("transaction already in the mempool")
+        } else if self.confirmed.contains(&hash) {
+            Err("transaction already confirmed")
+        } else if self.settled.contains_key(&data.get_auction_id()) {
+            Err("auction already settled")
+        } else if self.pending.contains_key(&data.get_auction_id()) {
+            Err("auction already has a pending settlement")
+        } else if !affordable(data.get_buyer()) || !affordable(data.get_seller()) {
+            Err("insufficient funds")
+        } else if self.entries.len() >= MAX_MEMPOOL {
+            Err("mempool is full")
+        } else {
+            self.add(data, now);
+            Ok(())
+        }
+    }
+
+    /* Transactions fitting in `max_size` bytes in the order they should be mined: highest fee per
+       byte first, then first come first served. Transactions too large for the remaining space
+       are skipped in favour of smaller ones. They stay in the pool until confirmed. */
+    pub fn select(&mut self, max_size: usize) -> Vec<Data> {
+        self.evict_expired(Utc::now().timestamp());
+        let mut entries: Vec<&Entry> = self.entries.values().collect();
+        entries.sort_by(|a, b| {
+            // compares fee / size without rounding
+            let rate_a = a.data.get_fee().units() as u128 * b.size as u128;
+            let rate_b = b.data.get_fee().units() as u128 * a.size as u128;
+            rate_b.cmp(&rate_a).then((a.arrival, a.sequence).cmp(&(b.arrival, b.sequence)))
+        });
+
+        let mut remaining = max_size;
+        let mut selected = Vec::new();
+        for entry in entries {
+            if entry.size <= remaining {
+                remaining -= entry.size;
+                selected.push(entry.data.clone());
+            }
+        }
+        selected
+    }
+
+    // mirrors a main chain update, disconnected transactions get a fresh arrival time
+    pub fn apply(&mut self, reorg: &Reorg) {
+        let now = Utc::now().timestamp();
+        for block in reorg.disconnected.iter() {
+            for data in block.transactions.iter().filter(|t| !t.is_coinbase()) {
+                self.disconnect(data);
+                if !self.settled.contains_key(&data.get_auction_id()) && !self.pending.contains_key(&data.get_auction_id()) {
+                    self.add(data.clone(), now);
+                }
+            }
+        }
+        for block in reorg.connected.iter() {
+            self.connect(block);
+        }
+    }
+
+    // what an account spends in the pool, ignoring what it receives, None if the sum overflows
+    fn pending_spend(&self, account: NodeID) -> Option<Amount> {
+        self.entries.values().try_fold(Amount::ZERO, |total, e| total.checked_add(spent_by(&e.data, account)?))
+    }
+
+    fn add(&mut self, data: Data, arrival: i64) {
+        self.sequence += 1;
+        self.pending.insert(data.get_auction_id(), data.hash());
+        self.entries.insert(data.hash(), Entry { size: data.size(), data, arrival, sequence: self.sequence });
+    }
+
+    fn remove(&mut self, hash: &H256) {
+        if let Some(entry) = self.entries.remove(hash) {
+            self.pending.remove(&entry.data.get_auction_id());
+        }
+    }
+
+    fn connect(&mut self, block: &Block) {
+        // coinbases don't settle anything
+        for data in block.transactions.iter().filter(|t| !t.is_coinbase()) {
+            self.confirm(data);
+            // a different settlement of the same auction can't be mined anymore
+            if let Some(pending) = self.pending.get(&data.get_auction_id()).copied() {
+                self.remove(&pending);
+            }
+        }
+    }
+
+    fn confirm(&mut self, data: &Data) {
+        self.confirmed.insert(data.hash());
+        *self.settled.entry(data.get_auction_id()).or_default() += 1;
+    }
+
+    fn disconnect(&mut self, data: &Data) {
+        self.confirmed.remove(&data.hash());
+        let auction_id = data.get_auction_id();
+        if let Some(count) = self.settled.get_mut(&auction_id) {
+            *count -= 1;
+            if *count == 0 {
+                self.settled.remove(&auction_id);
+            }
+        }
+    }
+
+    fn evict_expired(&mut self, now: i64) {
+        let expired: Vec<H256> = self.entries.iter()
+            .filter(|(_, e)| now - e.arrival > MEMPOOL_EXPIRY)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired {
+            self.remove(&hash);
+        }
+    }
+}
+
+// the buyer pays the amount and the seller pays the fee, None if that overflows
+fn spent_by(data: &Data, account: NodeID) -> Option<Amount> {
+    let mut spent = Amount::ZERO;
+    if data.get_buyer() == account && data.get_seller() != account {
+        spent = data.get_amount();
+    }
+    if data.get_seller() == account {
+        spent = spent.checked_add(data.get_fee())?;
+    }
+    Some(spent)
+}
+
+fn received_by(data: &Data, account: NodeID) -> Amount {
+    if data.get_seller() == account && data.get_buyer() != account {
+        data.get_amount()
+    } else {
+        Amount::ZERO
+    }
+}
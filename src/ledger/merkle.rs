@@ -0,0 +1,98 @@
+use openssl::sha::Sha256;
+use primitive_types::H256;
+
+// leaves and inner nodes are hashed with different prefixes so an inner node can't be passed off as a leaf
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub index: u64,
+    pub leaves: u64,
+    pub siblings: Vec<H256>,
+}
+
+impl MerkleProof {
+    /* Recomputes the root from the leaf and the sibling path. The path has to fit a tree of that
+       many leaves, and since leaves are unique only the last node of an odd level is paired with
+       itself, so a proof can't name the duplicated node past the last leaf. */
+    pub fn verify(&self, root: H256, leaf: H256) -> bool {
+        if self.index >= self.leaves {
+            return false;
+        }
+
+        let mut hash = hash_leaf(leaf);
+        let mut index = self.index;
+        let mut width = self.leaves;
+
+        for sibling in self.siblings.iter() {
+            if width == 1 || (*sibling == hash) != (width % 2 == 1 && index == width - 1) {
+                return false;
+            }
+            hash = if index & 1 == 0 {
+                hash_node(hash, *sibling)
+            } else {
+                hash_node(*sibling, hash)
+            };
+            index /= 2;
+            width = width.div_ceil(2);
+        }
+
+        width == 1 && hash == root
+    }
+}
+
+/* The root of an empty tree is zero, odd levels duplicate their last node. Leaves have to be
+   unique: [a, b, c] and [a, b, c, c] have the same root. */
+pub fn merkle_root(leaves: &[H256]) -> H256 {
+    if leaves.is_empty() {
+        return H256::zero();
+    }
+
+    let mut level: Vec<H256> = leaves.iter().map(|l| hash_leaf(*l)).collect();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+pub fn merkle_proof(leaves: &[H256], index: usize) -> Option<MerkleProof> {
+    if index >= leaves.len() {
+        return None;
+    }
+
+    let mut siblings = Vec::new();
+    let mut level: Vec<H256> = leaves.iter().map(|l| hash_leaf(*l)).collect();
+    let mut position = index;
+
+    while level.len() > 1 {
+        let sibling = position ^ 1;
+        siblings.push(*level.get(sibling).unwrap_or(&level[position]));
+        level = next_level(&level);
+        position /= 2;
+    }
+
+    Some(MerkleProof { index: index as u64, leaves: leaves.len() as u64, siblings })
+}
+
+fn next_level(level: &[H256]) -> Vec<H256> {
+    level
+        .chunks(2)
+        .map(|pair| hash_node(pair[0], *pair.get(1).unwrap_or(&pair[0])))
+        .collect()
+}
+
+fn hash_leaf(leaf: H256) -> H256 {
+    let mut hasher = Sha256::new();
+    hasher.update(&[LEAF_PREFIX]);
+    hasher.update(leaf.as_bytes());
+    H256::from(hasher.finish())
+}
+
+fn hash_node(left: H256, right: H256) -> H256 {
+    let mut hasher = Sha256::new();
+    hasher.update(&[NODE_PREFIX]);
+    hasher.update(left.as_bytes());
+    hasher.update(right.as_bytes());
+    H256::from(hasher.finish())
+}
@@ -1,33 +1,182 @@
+use std::{collections::HashSet, sync::Arc, time::{Duration, Instant}};
+
 use parking_lot::{Mutex, RwLock};
-use super::block::{Block, Chain, Data};
+use primitive_types::H256;
+use tokio::sync::broadcast;
+use crate::{datadir::data_path, p2p::key::NodeID};
+
+use super::{amount::Amount, block::{total_fees, tx_hashes, Block, Chain, Data, InclusionProof, Reorg, MAX_BLOCK_SIZE}, consensus::Consensus, genesis::genesis, mempool::Mempool, pool::Job, reward::reward_schedule, snapshot::{retention, Snapshot, SNAPSHOT}, storage::{BlockStore, BLOCK_INDEX, BLOCK_LOG, HEADER_INDEX, HEADER_LOG}, sync::{SyncState, MAX_HEADERS}};
+
+const REORG_EVENTS: usize = 16;
+// blocks added between two prunings of the block log
+const PRUNE_BATCH: u64 = 64;
+
+#[derive(Debug, Clone, Default)]
+pub struct MiningStats {
+    pub sealed: u64,
+    pub cancelled: u64,
+    pub hashes: u64,
+    // hashes per second over the last sealing attempt
+    pub hashrate: f64,
+}
+
+impl MiningStats {
+    fn record(&mut self, hashes: u64, elapsed: Duration, sealed: bool) {
+        if sealed {
+            self.sealed += 1;
+        } else {
+            self.cancelled += 1;
+        }
+        self.hashes += hashes;
+        self.hashrate = hashes as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+    }
+}
+
+// the block being sealed, identified by the hashes of its transactions
+#[derive(Debug)]
+struct MiningJob {
+    transactions: HashSet<H256>,
+    job: Arc<Job>,
+}
 
 #[derive(Debug)]
-pub struct Miner {
-    transactions: Mutex<Vec<Data>>,
+pub struct Miner<C: Consensus> {
+    consensus: C,
+    // credited with the rewards of the blocks we mine
+    beneficiary: NodeID,
+    mempool: Mutex<Mempool>,
     blocks_to_validate: Mutex<Vec<Block>>,
     blockchain: RwLock<Chain>,
+    store: Mutex<BlockStore>,
+    reorgs: broadcast::Sender<Reorg>,
+    // held while sealing so every block builds on the latest tip
+    mining: Mutex<()>,
+    current_job: Mutex<Option<MiningJob>>,
+    stats: Mutex<MiningStats>,
+    sync: Mutex<SyncState>,
+    // light clients only keep the headers, they don't mine or track balances
+    light: bool,
 }
 
-impl Miner {
-    pub fn new() -> Miner {
+impl<C: Consensus> Miner<C> {
+    pub fn new(consensus: C, beneficiary: NodeID) -> Miner<C> {
+        let (mut store, blocks) = BlockStore::open(&data_path(BLOCK_LOG), &data_path(BLOCK_INDEX)).expect("Could not open block storage");
+        check_genesis(&blocks, &data_path(BLOCK_LOG));
+        let snapshot = Snapshot::load(&data_path(SNAPSHOT)).expect("Could not read the snapshot");
+        let chain = match snapshot {
+            // bootstrapping, the snapshot's headers stand in for the blocks before it
+            Some(snapshot) if blocks.is_empty() => {
+                Chain::validate_header_chain(&snapshot.headers, &consensus).expect("Invalid snapshot headers");
+                for header in snapshot.headers.iter() {
+                    store.append(header).expect("Could not store snapshot headers");
+                }
+                println!("Bootstrapped from the snapshot at height {}", snapshot.height);
+                let headers = snapshot.headers.clone();
+                Chain::from_snapshot(snapshot, &headers).expect("Invalid snapshot")
+            },
+            Some(snapshot) => {
+                println!("Loaded {} blocks from storage", blocks.len());
+                Chain::from_snapshot(snapshot, &blocks).unwrap_or_else(|e| {
+                    println!("Ignoring the snapshot: {}", e);
+                    Chain::from_blocks(blocks)
+                })
+            },
+            None if blocks.is_empty() => {
+                let chain = Chain::new();
+                store.append(&chain.blocks[0]).expect("Could not store genesis block");
+                chain
+            },
+            None => {
+                println!("Loaded {} blocks from storage", blocks.len());
+                Chain::from_blocks(blocks)
+            },
+        };
+        Miner::with_chain(consensus, beneficiary, chain, store, false)
+    }
+
+    // follows the chain with the headers alone, stored apart from a full node's blocks
+    pub fn new_light(consensus: C, beneficiary: NodeID) -> Miner<C> {
+        let (mut store, headers) = BlockStore::open(&data_path(HEADER_LOG), &data_path(HEADER_INDEX)).expect("Could not open header storage");
+        check_genesis(&headers, &data_path(HEADER_LOG));
+        let chain = if headers.is_empty() {
+            let chain = Chain::from_blocks(vec![genesis().header_only()]);
+            store.append(&chain.blocks[0]).expect("Could not store genesis header");
+            chain
+        } else {
+            println!("Loaded {} headers from storage", headers.len());
+            Chain::from_blocks(headers)
+        };
+        Miner::with_chain(consensus, beneficiary, chain, store, true)
+    }
+
+    fn with_chain(consensus: C, beneficiary: NodeID, mut chain: Chain, store: BlockStore, light: bool) -> Miner<C> {
+        chain.count_work(&consensus);
         Miner {
-            transactions: Mutex::new(Vec::new()),
+            consensus,
+            beneficiary,
+            mempool: Mutex::new(Mempool::from_chain(&chain)),
             blocks_to_validate: Mutex::new(Vec::new()),
-            blockchain: RwLock::new(Chain::new()),
+            blockchain: RwLock::new(chain),
+            store: Mutex::new(store),
+            reorgs: broadcast::channel(REORG_EVENTS).0,
+            mining: Mutex::new(()),
+            current_job: Mutex::new(None),
+            stats: Mutex::new(MiningStats::default()),
+            sync: Mutex::new(SyncState::default()),
+            light,
         }
     }
+
+    pub fn is_light(&self) -> bool {
+        self.light
+    }
+
+    // every change to the main chain is published here, including blocks simply appended to the tip
+    pub fn subscribe_reorgs(&self) -> broadcast::Receiver<Reorg> {
+        self.reorgs.subscribe()
+    }
     pub fn print_blockchain(&self) {
         let lock = self.blockchain.try_read().unwrap();
         println!("Blockchain: {:?}", lock.blocks)
     }
 
-    pub fn get_chain(&self) -> Chain {
-        self.blockchain.read().get_chain()
+    pub fn print_mining_stats(&self) {
+        let stats = self.stats.lock().clone();
+        println!("Mining: {} blocks sealed, {} cancelled, {} hashes, {:.0} H/s on the last block, {} pending transactions",
+            stats.sealed, stats.cancelled, stats.hashes, stats.hashrate, self.mempool.lock().len());
+    }
+
+    pub fn prove_settlement(&self, auction_id: H256) -> Option<InclusionProof> {
+        self.blockchain.read().prove_settlement(auction_id)
+    }
+
+    // number of confirmations of the proven settlement's block
+    pub fn verify_inclusion(&self, proof: &InclusionProof) -> Result<u64, &'static str> {
+        self.blockchain.read().verify_inclusion(proof)
+    }
+
+    pub fn find_settlement(&self, auction_id: H256) -> Option<(u64, Data)> {
+        let lock = self.blockchain.read();
+        lock.find_settlement(&auction_id).map(|(height, data)| (height, data.clone()))
+    }
+
+    pub fn account_transactions(&self, account: NodeID) -> Vec<(u64, Data)> {
+        let lock = self.blockchain.read();
+        lock.account_transactions(&account).into_iter().map(|(height, data)| (height, data.clone())).collect()
+    }
+
+    // confirmed balance of an account and the height it was computed at
+    pub fn balance(&self, account: NodeID) -> (Amount, u64) {
+        let lock = self.blockchain.read();
+        (lock.accounts().balance(account), lock.tip().id)
     }
 
-    pub fn store_transaction(&self, t: Data) {
-        let mut lock = self.transactions.lock();
-        lock.push(t);
+    pub fn store_transaction(&self, t: Data) -> Result<(), &'static str> {
+        if self.light {
+            return Err("light clients don't keep a mempool");
+        }
+        let chain = self.blockchain.read();
+        self.mempool.lock().insert(t, chain.accounts())
     }
 
     pub fn store_block(&self,block: Block) {
@@ -35,19 +184,59 @@ impl Miner {
         lock.push(block);
     }
 
-    pub fn mine(&self) -> Block {
-       let transaction;
-       {
-           transaction = self.transactions.lock().pop();
+    /* Batches the pending transactions paying the highest fee rates into a single block of up to
+       MAX_BLOCK_SIZE bytes, after a coinbase paying the subsidy and the fees to the beneficiary.
+       Blocks the calling thread while the block is sealed, sealing is cancelled as soon as a valid
+       block confirming any of the same transactions reaches the main chain. */
+    pub fn mine(&self) -> Result<Block, &'static str> {
+       if self.light {
+           return Err("light clients don't mine");
        }
-       match transaction {
-            Some(t) => 
-            {
-                self.blockchain.write().mine(t)
+       let _mining = self.mining.lock();
+       let transactions = {
+           let mut mempool = self.mempool.lock();
+           if mempool.is_empty() {
+               return Err("no pending transactions");
+           }
+           // the coinbase's size doesn't depend on its amount or height
+           mempool.select(MAX_BLOCK_SIZE - Data::coinbase(self.beneficiary, Amount::ZERO, 0).size())
+       };
 
-            },
-            None => todo!(),
-        }
+       let (transactions, mut header) = {
+           let chain = self.blockchain.read();
+           // pending transactions can stop being affordable after a reorg, they wait in the mempool until they expire
+           let mut accounts = chain.accounts().clone();
+           let transactions: Vec<Data> = transactions.into_iter().filter(|t| accounts.apply_transaction(t).is_ok()).collect();
+           if transactions.is_empty() {
+               return Err("no affordable pending transactions");
+           }
+           let height = chain.tip().id + 1;
+           let reward = total_fees(&transactions)
+               .and_then(|fees| fees.checked_add(reward_schedule().subsidy(height)))
+               .ok_or("block reward overflow")?;
+           let mut block_transactions = vec![Data::coinbase(self.beneficiary, reward, height)];
+           block_transactions.extend(transactions);
+           let header = chain.block_template(&block_transactions, &self.consensus)?;
+           (block_transactions, header)
+       };
+
+       let job = Arc::new(Job::default());
+       *self.current_job.lock() = Some(MiningJob {
+           transactions: tx_hashes(&transactions[1..]).into_iter().collect(),
+           job: job.clone(),
+       });
+       let started = Instant::now();
+       let sealed = self.consensus.seal(&mut header, &job);
+       *self.current_job.lock() = None;
+       self.stats.lock().record(job.hashes(), started.elapsed(), sealed.is_some());
+
+       let (hash, seal) = sealed.ok_or("mining cancelled by a competing block")?;
+       let block = Block::new(header, hash, seal, transactions);
+
+       let mut lock = self.blockchain.write();
+       let reorg = lock.add_block(block.clone(), &self.consensus)?;
+       self.apply(&mut lock, reorg);
+       Ok(block)
     }
 
     pub fn validate_blocks(&self) -> Result<(), &'static str> {
@@ -56,22 +245,157 @@ impl Miner {
             block = self.blocks_to_validate.lock().pop();
         }
         match block {
-             Some(b) => 
+             Some(b) if self.store.lock().contains(&b.hash) => Err("Block already stored"),
+             Some(b) =>
              {
-                if self.blockchain.write().add_block(b){ 
-                    Ok(())
+                let mut lock = self.blockchain.write();
+                let reorg = if self.light {
+                    lock.connect_headers(vec![b.header_only()], &self.consensus)?
                 } else {
-                    Err("Invalid block")
-                }
- 
+                    lock.add_block(b, &self.consensus)?
+                };
+                self.apply(&mut lock, reorg);
+                Ok(())
              },
              None => Err("No block"),
          }
     }
 
-    pub fn choose_chain(&self, mut chains: Vec<Vec<Block>>) {
-        chains.sort_by(|a,b| b.len().cmp(&a.len()));
+    pub fn locator(&self) -> Vec<H256> {
+        self.blockchain.read().locator()
+    }
+
+    pub fn headers_after(&self, locator: &[H256]) -> Vec<Block> {
+        self.blockchain.read().headers_after(locator, MAX_HEADERS)
+    }
+
+    // main chain or side blocks with the given hashes, unknown and pruned ones are skipped
+    pub fn get_blocks(&self, hashes: &[H256]) -> Vec<Block> {
+        let lock = self.blockchain.read();
+        if self.light {
+            return Vec::new();
+        }
+        hashes.iter().filter_map(|hash| lock.body(hash).cloned()).collect()
+    }
+
+    // writes the state of the main chain at a height for other nodes to bootstrap from
+    pub fn snapshot(&self, height: u64) -> Result<String, &'static str> {
+        if self.light {
+            return Err("light clients don't keep the chain state");
+        }
+        let snapshot = self.blockchain.read().snapshot(height)?;
+        let path = data_path(&format!("snapshot-{}.dat", height));
+        snapshot.save(&path).map_err(|_| "could not write the snapshot")?;
+        Ok(path)
+    }
+
+    // whether the headers became the branch being synced
+    // light clients switch to the headers right away, there are no bodies to download
+    pub fn sync_headers(&self, headers: Vec<Block>) -> Result<bool, &'static str> {
+        if self.light {
+            let mut lock = self.blockchain.write();
+            let reorg = lock.connect_headers(headers, &self.consensus)?;
+            let kept = !reorg.is_empty();
+            self.apply(&mut lock, reorg);
+            return Ok(kept);
+        }
+        let lock = self.blockchain.read();
+        self.sync.lock().offer(headers, &lock, &self.consensus)
+    }
+
+    pub fn missing_bodies(&self) -> Vec<H256> {
+        let lock = self.blockchain.read();
+        self.sync.lock().missing(&lock)
+    }
+
+    pub fn sync_body(&self, block: Block) -> Result<(), &'static str> {
+        self.sync.lock().add_body(block)
+    }
+
+    /* Connects the synced blocks whose bodies arrived, in order, switching the main chain once
+       the branch has more work. Returns whether the sync is complete, an invalid block abandons it. */
+    pub fn connect_synced(&self) -> Result<bool, &'static str> {
+        let mut sync = self.sync.lock();
+        let mut lock = self.blockchain.write();
+        for block in sync.take_ready(&lock) {
+            match lock.add_block(block, &self.consensus) {
+                Ok(reorg) => self.apply(&mut lock, reorg),
+                Err(e) => {
+                    sync.reset();
+                    return Err(e);
+                },
+            }
+        }
+
+        let complete = sync.is_complete(&lock);
+        if complete {
+            sync.reset();
+        }
+        Ok(complete)
+    }
+
+    // stops sealing a block whose transactions were just confirmed by someone else
+    fn cancel_competing(&self, reorg: &Reorg) {
+        if let Some(current) = self.current_job.lock().as_ref() {
+            let confirmed = reorg.connected.iter().flat_map(|b| b.transactions.iter());
+            if confirmed.map(|t| t.hash()).any(|h| current.transactions.contains(&h)) {
+                println!("A competing block confirmed the transactions being mined, cancelling");
+                current.job.cancel();
+            }
+        }
+    }
+
+    // mirrors a main chain change in storage and notifies the subscribers
+    fn apply(&self, chain: &mut Chain, reorg: Reorg) {
+        if reorg.is_empty() {
+            return;
+        }
+        self.cancel_competing(&reorg);
+        self.mempool.lock().apply(&reorg);
+
+        let mut store = self.store.lock();
+        let keep = chain.blocks.len() - reorg.connected.len();
+        if let Err(e) = store.truncate(keep as u64) {
+            println!("Failed to remove disconnected blocks from storage: {}", e);
+        }
+        for block in reorg.connected.iter() {
+            if let Err(e) = store.append(block) {
+                println!("Failed to store block with id {}: {}", block.id, e);
+            }
+        }
+        self.prune(chain, &mut store);
+
+        // sending only fails when nobody is subscribed
+        let _ = self.reorgs.send(reorg);
+    }
+
+    /* Drops old bodies following the retention policy, once a snapshot to restart from is saved.
+       Pruning rewrites the block log, so it only happens every PRUNE_BATCH blocks. */
+    fn prune(&self, chain: &mut Chain, store: &mut BlockStore) {
+        let keep = match retention() {
+            Some(keep) if !self.light => keep,
+            _ => return,
+        };
+        if chain.tip().id + 1 < chain.pruned_height() + keep + PRUNE_BATCH || !chain.prune(keep) {
+            return;
+        }
+
+        let saved = chain.snapshot(chain.pruned_height() - 1).map(|s| s.save(&data_path(SNAPSHOT)));
+        match saved {
+            Ok(Ok(())) => match store.rewrite(&chain.blocks) {
+                Ok(()) => println!("Pruned the block bodies below height {}", chain.pruned_height()),
+                Err(e) => println!("Failed to rewrite the block log: {}", e),
+            },
+            // the log keeps every body, so a restart goes back to the previous snapshot
+            Ok(Err(e)) => println!("Failed to save the snapshot: {}", e),
+            Err(e) => println!("Failed to take a snapshot: {}", e),
+        }
+    }
+}
 
-        self.blockchain.write().replace(chains.pop().unwrap());
+// a log from another network can't be reused, the node has to start over with an empty one
+fn check_genesis(blocks: &[Block], log: &str) {
+    if blocks.first().is_some_and(|b| b.hash != genesis().hash) {
+        panic!("{} starts from a different genesis, remove it and its index to join this network", log);
     }
 }
@@ -1,2 +1,17 @@
+pub mod accounts;
+pub mod amount;
+pub mod authority;
 pub mod block;
-pub mod miner;
\ No newline at end of file
+pub mod consensus;
+pub mod difficulty;
+pub mod genesis;
+pub mod index;
+pub mod mempool;
+pub mod merkle;
+pub mod miner;
+pub mod pool;
+pub mod reward;
+pub mod snapshot;
+pub mod storage;
+pub mod sync;
+pub mod timestamp;
\ No newline at end of file
@@ -0,0 +1,131 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+};
+
+use primitive_types::H256;
+use rand::Rng;
+
+use super::{block::BlockHeader, difficulty::meets_target};
+
+// hashes tried between two checks of the stop flags
+const BATCH: u64 = 1024;
+
+// a sealing attempt that can be cancelled from another thread, counting the hashes it tried
+#[derive(Debug, Default)]
+pub struct Job {
+    cancelled: AtomicBool,
+    hashes: AtomicU64,
+}
+
+impl Job {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    pub fn hashes(&self) -> u64 {
+        self.hashes.load(Ordering::Relaxed)
+    }
+}
+
+// one worker's share of a search: nonces start, start + stride, start + 2 * stride, ...
+struct Task {
+    header: BlockHeader,
+    start: u64,
+    stride: u64,
+    job: Arc<Job>,
+    // set once any worker finds a solution
+    solved: Arc<AtomicBool>,
+    found: mpsc::Sender<(H256, u64)>,
+}
+
+impl Task {
+    fn run(mut self) {
+        self.header.nonce = self.start;
+        loop {
+            for tried in 1..=BATCH {
+                let hash = self.header.hash();
+                if meets_target(hash.as_bytes(), self.header.target) {
+                    self.job.hashes.fetch_add(tried, Ordering::Relaxed);
+                    self.solved.store(true, Ordering::Release);
+                    let _ = self.found.send((hash, self.header.nonce));
+                    return;
+                }
+                self.header.nonce = self.header.nonce.wrapping_add(self.stride);
+            }
+            self.job.hashes.fetch_add(BATCH, Ordering::Relaxed);
+
+            if self.solved.load(Ordering::Acquire) || self.job.is_cancelled() {
+                return;
+            }
+        }
+    }
+}
+
+/* Threads that search for proof of work nonces, one per core. They live as long as the pool
+   and split the nonce space of every search between them. */
+#[derive(Debug)]
+pub struct WorkerPool {
+    workers: Vec<mpsc::Sender<Task>>,
+}
+
+impl WorkerPool {
+    pub fn new() -> WorkerPool {
+        let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        let workers = (0..threads)
+            .map(|i| {
+                let (sender, tasks) = mpsc::channel::<Task>();
+                thread::Builder::new()
+                    .name(format!("miner-{}", i))
+                    .spawn(move || {
+                        while let Ok(task) = tasks.recv() {
+                            task.run();
+                        }
+                    })
+                    .expect("Could not start mining thread");
+                sender
+            })
+            .collect();
+
+        WorkerPool { workers }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    // blocks until a nonce puts the header's hash under its target, None if the job was cancelled first
+    pub fn search(&self, header: &BlockHeader, job: &Arc<Job>) -> Option<(H256, u64)> {
+        let (found, results) = mpsc::channel();
+        let solved = Arc::new(AtomicBool::new(false));
+        let start: u64 = rand::thread_rng().gen();
+        let stride = self.workers.len() as u64;
+
+        for (i, worker) in self.workers.iter().enumerate() {
+            let task = Task {
+                header: header.clone(),
+                start: start.wrapping_add(i as u64),
+                stride,
+                job: job.clone(),
+                solved: solved.clone(),
+                found: found.clone(),
+            };
+            if worker.send(task).is_err() {
+                println!("mining thread {} stopped", i);
+            }
+        }
+        drop(found);
+
+        // every sender is dropped once all the workers gave up, which only happens on cancellation
+        let result = results.recv().ok();
+        solved.store(true, Ordering::Release);
+        result
+    }
+}
@@ -0,0 +1,62 @@
+use std::{fs, sync::OnceLock};
+
+use crate::datadir::data_path;
+
+use super::amount::Amount;
+
+// "<initial reward> <halving interval in blocks>", the defaults below apply if the file doesn't exist
+pub const REWARDS: &str = "rewards";
+
+const INITIAL_REWARD: Amount = Amount::from_whole(50);
+const HALVING_INTERVAL: u64 = 210_000;
+
+/* Block subsidy paid by the coinbase of every block: the initial reward halves every
+   `halving_interval` blocks until it reaches zero. An interval of 0 never halves it, and the
+   genesis block doesn't pay anything. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RewardSchedule {
+    pub initial: Amount,
+    pub halving_interval: u64,
+}
+
+impl Default for RewardSchedule {
+    fn default() -> Self {
+        RewardSchedule {
+            initial: INITIAL_REWARD,
+            halving_interval: HALVING_INTERVAL,
+        }
+    }
+}
+
+impl RewardSchedule {
+    pub fn subsidy(&self, height: u64) -> Amount {
+        if height == 0 {
+            return Amount::ZERO;
+        }
+        let halvings = height.checked_div(self.halving_interval).unwrap_or(0);
+        let units = u32::try_from(halvings)
+            .ok()
+            .and_then(|h| self.initial.units().checked_shr(h))
+            .unwrap_or(0);
+        Amount::from_units(units)
+    }
+}
+
+// read once, every node of a network has to use the same schedule
+pub fn reward_schedule() -> &'static RewardSchedule {
+    static SCHEDULE: OnceLock<RewardSchedule> = OnceLock::new();
+    SCHEDULE.get_or_init(|| match fs::read_to_string(data_path(REWARDS)) {
+        Ok(contents) => parse_schedule(&contents).unwrap_or_else(|| {
+            println!("Invalid reward schedule in {}, using the default one", data_path(REWARDS));
+            RewardSchedule::default()
+        }),
+        Err(_) => RewardSchedule::default(),
+    })
+}
+
+fn parse_schedule(contents: &str) -> Option<RewardSchedule> {
+    let mut fields = contents.split_whitespace();
+    let initial = fields.next()?.parse::<Amount>().ok()?;
+    let halving_interval = fields.next()?.parse::<u64>().ok()?;
+    Some(RewardSchedule { initial, halving_interval })
+}
@@ -0,0 +1,75 @@
+use std::{fs, io, sync::OnceLock};
+
+use openssl::sha::sha256;
+use primitive_types::H256;
+
+use crate::{datadir::data_path, p2p::{key::NodeID, util::{decode_snapshot, encode_snapshot}}};
+
+use super::{amount::Amount, block::{Block, Data}, storage::write_atomic};
+
+// loaded on startup from the data directory, the node resumes or bootstraps from it
+pub const SNAPSHOT: &str = "snapshot.dat";
+// number of recent blocks whose bodies are kept, every body is kept if the file doesn't exist
+pub const RETENTION: &str = "retention";
+
+// bodies that are always kept, reorgs can't go deeper than the oldest body
+const MIN_RETENTION: u64 = 32;
+
+/* Derived state of the main chain at a height: the balances, the settlement of every auction
+   settled so far and the headers from the genesis to that height. Blocks after the height are
+   replayed on top of it, so the blocks before it don't need their bodies. Stored as
+   [sha256 of payload][payload]. */
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub height: u64,
+    // hash of the block at `height`
+    pub hash: H256,
+    pub balances: Vec<(NodeID, Amount)>,
+    // the latest settlement of every auction and the height it was confirmed at
+    pub settlements: Vec<(u64, Data)>,
+    // header-only blocks, from the genesis to `height`
+    pub headers: Vec<Block>,
+}
+
+impl Snapshot {
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let payload = encode_snapshot(self);
+        let mut bytes = sha256(&payload).to_vec();
+        bytes.extend_from_slice(&payload);
+        write_atomic(path, &bytes)
+    }
+
+    // Ok(None) means there's no snapshot at that path
+    pub fn load(path: &str) -> io::Result<Option<Snapshot>> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "corrupted snapshot");
+        if bytes.len() < 32 || sha256(&bytes[32..]) != bytes[..32] {
+            return Err(invalid());
+        }
+        let snapshot = decode_snapshot(&bytes[32..]).ok_or_else(invalid)?;
+        if snapshot.headers.len() as u64 != snapshot.height + 1 || snapshot.headers.last().map(|h| h.hash) != Some(snapshot.hash) {
+            return Err(invalid());
+        }
+        Ok(Some(snapshot))
+    }
+}
+
+// read once, None keeps every body
+pub fn retention() -> Option<u64> {
+    static RETAINED: OnceLock<Option<u64>> = OnceLock::new();
+    *RETAINED.get_or_init(|| {
+        let contents = fs::read_to_string(data_path(RETENTION)).ok()?;
+        match contents.trim().parse::<u64>() {
+            Ok(blocks) => Some(blocks.max(MIN_RETENTION)),
+            Err(_) => {
+                println!("Invalid retention in {}, keeping every block", data_path(RETENTION));
+                None
+            },
+        }
+    })
+}
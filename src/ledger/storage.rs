@@ -0,0 +1,219 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+};
+
+use openssl::sha::sha256;
+use primitive_types::H256;
+
+use crate::p2p::util::{decode_block, encode_block};
+
+use super::block::Block;
+
+// in the data directory
+pub const BLOCK_LOG: &str = "blocks.dat";
+pub const BLOCK_INDEX: &str = "blocks.idx";
+// header-only blocks of a light client
+pub const HEADER_LOG: &str = "headers.dat";
+pub const HEADER_INDEX: &str = "headers.idx";
+
+// every record in the log is [payload length (u32 BE)][sha256 of payload][payload]
+const RECORD_HEADER: usize = 4 + 32;
+// every entry in the index is [block hash][record offset (u64 BE)], the height is the entry position
+const INDEX_ENTRY: usize = 32 + 8;
+
+#[derive(Debug)]
+pub struct BlockStore {
+    log_path: String,
+    index_path: String,
+    log: File,
+    offsets: Vec<u64>,
+    heights: HashMap<H256, u64>,
+}
+
+impl BlockStore {
+    /* Opens (or creates) the block log, returning the store and every valid block found on disk.
+       Records that fail the checksum or don't link to the previous block are treated as a torn
+       write: the log is truncated at that point and the index is rebuilt from what remains. */
+    pub fn open(log_path: &str, index_path: &str) -> io::Result<(BlockStore, Vec<Block>)> {
+        let mut log = OpenOptions::new().read(true).append(true).create(true).open(log_path)?;
+        let (blocks, offsets, valid_len) = read_log(&mut log)?;
+        let log_len = log.metadata()?.len();
+
+        if valid_len < log_len {
+            println!("block storage corrupted at offset {}, discarding {} bytes", valid_len, log_len - valid_len);
+            log.set_len(valid_len)?;
+            log.sync_all()?;
+        }
+
+        let mut heights = HashMap::with_capacity(blocks.len());
+        for (height, block) in blocks.iter().enumerate() {
+            heights.insert(block.hash, height as u64);
+        }
+
+        let store = BlockStore {
+            log_path: log_path.to_owned(),
+            index_path: index_path.to_owned(),
+            log,
+            offsets,
+            heights,
+        };
+
+        let expected = store.index_bytes(&blocks);
+        if fs::read(index_path).ok().as_deref() != Some(expected.as_slice()) {
+            write_atomic(index_path, &expected)?;
+        }
+
+        Ok((store, blocks))
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.heights.contains_key(hash)
+    }
+
+    // appends a block to the log and to the index, both are flushed to disk before returning
+    pub fn append(&mut self, block: &Block) -> io::Result<()> {
+        let offset = self.log.metadata()?.len();
+        self.log.write_all(&encode_record(block))?;
+        self.log.sync_data()?;
+
+        let mut index = OpenOptions::new().append(true).create(true).open(&self.index_path)?;
+        index.write_all(&index_entry(block.hash, offset))?;
+        index.sync_data()?;
+
+        self.heights.insert(block.hash, self.offsets.len() as u64);
+        self.offsets.push(offset);
+        Ok(())
+    }
+
+    // drops every block from the given height onwards, used when blocks are disconnected by a reorg
+    pub fn truncate(&mut self, height: u64) -> io::Result<()> {
+        let offset = match self.offsets.get(height as usize) {
+            Some(offset) => *offset,
+            None => return Ok(()),
+        };
+
+        self.log.set_len(offset)?;
+        self.log.sync_all()?;
+
+        let index = OpenOptions::new().write(true).open(&self.index_path)?;
+        index.set_len(height * INDEX_ENTRY as u64)?;
+        index.sync_all()?;
+
+        self.offsets.truncate(height as usize);
+        self.heights.retain(|_, h| *h < height);
+        Ok(())
+    }
+
+    // replaces the whole log with the given blocks, used once old bodies are pruned
+    pub fn rewrite(&mut self, blocks: &[Block]) -> io::Result<()> {
+        let mut log = Vec::new();
+        let mut offsets = Vec::with_capacity(blocks.len());
+        for block in blocks.iter() {
+            offsets.push(log.len() as u64);
+            log.extend_from_slice(&encode_record(block));
+        }
+        write_atomic(&self.log_path, &log)?;
+        self.log = OpenOptions::new().read(true).append(true).open(&self.log_path)?;
+        self.offsets = offsets;
+        self.heights = blocks.iter().enumerate().map(|(height, b)| (b.hash, height as u64)).collect();
+
+        // a stale index is rebuilt by open, so a crash between the two writes is harmless
+        write_atomic(&self.index_path, &self.index_bytes(blocks))
+    }
+
+    fn index_bytes(&self, blocks: &[Block]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(blocks.len() * INDEX_ENTRY);
+        for (block, offset) in blocks.iter().zip(self.offsets.iter()) {
+            bytes.extend_from_slice(&index_entry(block.hash, *offset));
+        }
+        bytes
+    }
+}
+
+fn encode_record(block: &Block) -> Vec<u8> {
+    let payload = encode_block(block);
+    let mut record = Vec::with_capacity(RECORD_HEADER + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    record.extend_from_slice(&sha256(&payload));
+    record.extend_from_slice(&payload);
+    record
+}
+
+fn index_entry(hash: H256, offset: u64) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(INDEX_ENTRY);
+    entry.extend_from_slice(hash.as_bytes());
+    entry.extend_from_slice(&offset.to_be_bytes());
+    entry
+}
+
+/* reads a single record out of the `remaining` bytes of the log, Ok(None) means the record is
+   incomplete or corrupted. The length is checked before allocating, a corrupted one could ask for
+   gigabytes. */
+fn read_record<R: Read>(reader: &mut R, remaining: u64) -> io::Result<Option<Block>> {
+    let mut header = [0u8; RECORD_HEADER];
+    if let Err(e) = reader.read_exact(&mut header) {
+        return match e.kind() {
+            io::ErrorKind::UnexpectedEof => Ok(None),
+            _ => Err(e),
+        };
+    }
+
+    let len = u32::from_be_bytes(header[0..4].try_into().unwrap()) as usize;
+    if len as u64 > remaining.saturating_sub(RECORD_HEADER as u64) {
+        return Ok(None);
+    }
+    let mut payload = vec![0u8; len];
+    if let Err(e) = reader.read_exact(&mut payload) {
+        return match e.kind() {
+            io::ErrorKind::UnexpectedEof => Ok(None),
+            _ => Err(e),
+        };
+    }
+
+    if sha256(&payload) != header[4..] {
+        return Ok(None);
+    }
+
+    Ok(decode_block(&payload))
+}
+
+// returns the valid prefix of the log: its blocks, their offsets and its length in bytes
+fn read_log(log: &mut File) -> io::Result<(Vec<Block>, Vec<u64>, u64)> {
+    let len = log.metadata()?.len();
+    log.seek(SeekFrom::Start(0))?;
+    let mut reader = BufReader::new(log);
+    let mut blocks: Vec<Block> = Vec::new();
+    let mut offsets = Vec::new();
+    let mut offset = 0;
+
+    while offset < len {
+        let block = match read_record(&mut reader, len - offset)? {
+            Some(block) => block,
+            None => break,
+        };
+
+        if let Some(prev) = blocks.last() {
+            if block.prev_hash != prev.hash {
+                println!("block with id: {} in storage doesn't link to its predecessor", block.id);
+                break;
+            }
+        }
+
+        offsets.push(offset);
+        offset = reader.stream_position()?;
+        blocks.push(block);
+    }
+
+    Ok((blocks, offsets, offset))
+}
+
+// writes to a temporary file and renames it over the target so a crash never leaves a partial file
+pub fn write_atomic(path: &str, bytes: &[u8]) -> io::Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(bytes)?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
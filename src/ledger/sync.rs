@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+
+use primitive_types::{H256, U256};
+
+use super::{block::{Block, Chain}, consensus::Consensus};
+
+// most headers sent in one reply, a longer branch is synced over several rounds
+pub const MAX_HEADERS: usize = 512;
+// most locator hashes looked up, a locator only grows with the log of the chain's height
+pub const MAX_LOCATOR: usize = 64;
+
+/* Progress of a headers-first sync: the validated headers of the best branch peers announced and
+   the bodies downloaded for them so far. It survives failed rounds, so the next one only fetches
+   the bodies that are still missing. */
+#[derive(Debug, Default)]
+pub struct SyncState {
+    // header-only blocks from the fork with our main chain, in order
+    headers: Vec<Block>,
+    // cumulative work of the chain the headers lead to
+    work: U256,
+    bodies: HashMap<H256, Block>,
+}
+
+impl SyncState {
+    /* Validates the headers a peer sent and makes them the branch to sync if they lead to more
+       work than both our main chain and the branch being synced. Returns whether they were kept. */
+    pub fn offer(&mut self, headers: Vec<Block>, chain: &Chain, consensus: &dyn Consensus) -> Result<bool, &'static str> {
+        if headers.is_empty() {
+            return Ok(false);
+        }
+        let work = chain.validate_headers(&headers, consensus)?;
+        if work <= chain.work(consensus) || (!self.is_stale(chain) && work <= self.work) {
+            return Ok(false);
+        }
+
+        // bodies are kept by hash, so the ones already downloaded carry over to the new branch
+        self.bodies.retain(|hash, _| headers.iter().any(|h| h.hash == *hash));
+        self.headers = headers;
+        self.work = work;
+        Ok(true)
+    }
+
+    // hashes of the blocks whose bodies still have to be downloaded, in chain order
+    pub fn missing(&self, chain: &Chain) -> Vec<H256> {
+        self.headers
+            .iter()
+            .filter(|h| !self.bodies.contains_key(&h.hash) && !chain.contains(&h.hash))
+            .map(|h| h.hash)
+            .collect()
+    }
+
+    // accepts a downloaded block if it's the body of one of the headers being synced
+    pub fn add_body(&mut self, block: Block) -> Result<(), &'static str> {
+        let header = self.headers.iter().find(|h| h.hash == block.hash).ok_or("block wasn't requested")?;
+        if block.header() != header.header() || block.seal != header.seal {
+            Err("block doesn't match its header")
+        } else if block.has_duplicate_transactions() {
+            Err("duplicate transaction")
+        } else if block.compute_merkle_root() != block.merkle_root {
+            Err("invalid merkle root")
+        } else {
+            self.bodies.insert(block.hash, block);
+            Ok(())
+        }
+    }
+
+    // downloaded blocks that can be connected now, stopping at the first missing body
+    pub fn take_ready(&mut self, chain: &Chain) -> Vec<Block> {
+        let mut ready = Vec::new();
+        for header in self.headers.iter() {
+            if chain.contains(&header.hash) {
+                continue;
+            }
+            match self.bodies.remove(&header.hash) {
+                Some(block) => ready.push(block),
+                None => break,
+            }
+        }
+        ready
+    }
+
+    // done once every header is part of our chain
+    pub fn is_complete(&self, chain: &Chain) -> bool {
+        self.headers.iter().all(|h| chain.contains(&h.hash))
+    }
+
+    pub fn reset(&mut self) {
+        *self = SyncState::default();
+    }
+
+    // the branch no longer forks from our main chain, after a reorg
+    fn is_stale(&self, chain: &Chain) -> bool {
+        self.headers.first().is_none_or(|h| !chain.contains(&h.prev_hash))
+    }
+}
@@ -0,0 +1,126 @@
+use std::{fs, sync::OnceLock};
+
+use chrono::Utc;
+
+use crate::datadir::data_path;
+
+use super::block::{Ancestry, Block};
+
+// seconds a block's timestamp can be ahead of the local clock, the default below applies if the file doesn't exist
+pub const MAX_DRIFT: &str = "max_drift";
+
+// a block's timestamp has to be later than the median of this many blocks before it
+pub const MEDIAN_TIME_SPAN: usize = 11;
+const DEFAULT_DRIFT: i64 = 2 * 60 * 60;
+
+// median timestamp of `parent` and the blocks before it, on whatever branch it is
+pub fn median_time_past(parent: &Block, chain: &dyn Ancestry) -> i64 {
+    let mut timestamps = Vec::with_capacity(MEDIAN_TIME_SPAN);
+    let mut block = Some(parent);
+    while let Some(current) = block.filter(|_| timestamps.len() < MEDIAN_TIME_SPAN) {
+        timestamps.push(current.timestamp);
+        block = chain.get_block(&current.prev_hash);
+    }
+    timestamps.sort_unstable();
+    timestamps[timestamps.len() / 2]
+}
+
+/* The median keeps time moving forward even if some miners lie about it, while the drift bounds
+   how far ahead a miner can push it. A block refused for being in the future can be accepted
+   once our clock catches up. */
+pub fn check_timestamp(block: &Block, parent: &Block, chain: &dyn Ancestry) -> Result<(), &'static str> {
+    if block.timestamp <= median_time_past(parent, chain) {
+        Err("timestamp isn't after the median time of the previous blocks")
+    } else if block.timestamp > Utc::now().timestamp() + max_drift() {
+        Err("timestamp too far in the future")
+    } else {
+        Ok(())
+    }
+}
+
+// read once, nodes with a tighter drift than their peers refuse blocks the others accept
+pub fn max_drift() -> i64 {
+    static DRIFT: OnceLock<i64> = OnceLock::new();
+    *DRIFT.get_or_init(|| match fs::read_to_string(data_path(MAX_DRIFT)) {
+        Ok(contents) => match contents.trim().parse::<i64>() {
+            Ok(drift) if drift >= 0 => drift,
+            _ => {
+                println!("Invalid drift in {}, using the default one", data_path(MAX_DRIFT));
+                DEFAULT_DRIFT
+            },
+        },
+        Err(_) => DEFAULT_DRIFT,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use primitive_types::H256;
+
+    use super::*;
+    use crate::ledger::{block::{BlockHeader, Chain}, difficulty::initial_target};
+
+    fn block(parent: Option<&Block>, timestamp: i64) -> Block {
+        let header = BlockHeader {
+            id: parent.map_or(0, |p| p.id + 1),
+            prev_hash: parent.map_or(H256::zero(), |p| p.hash),
+            merkle_root: H256::zero(),
+            timestamp,
+            target: initial_target(),
+            nonce: 0,
+        };
+        Block::new(header.clone(), header.hash(), Vec::new(), Vec::new())
+    }
+
+    // MEDIAN_TIME_SPAN blocks whose timestamps aren't in order, the median is 600 and the tip's 1000
+    fn chain() -> Chain {
+        let mut blocks: Vec<Block> = Vec::new();
+        for timestamp in [100, 500, 200, 400, 300, 900, 600, 800, 700, 1100, 1000] {
+            blocks.push(block(blocks.last(), timestamp));
+        }
+        Chain::from_blocks(blocks)
+    }
+
+    #[test]
+    fn median_of_the_last_blocks() {
+        let chain = chain();
+        assert_eq!(median_time_past(chain.tip(), &chain), 600);
+    }
+
+    #[test]
+    fn timestamp_equal_to_the_median() {
+        let chain = chain();
+        assert_eq!(
+            check_timestamp(&block(Some(chain.tip()), 600), chain.tip(), &chain),
+            Err("timestamp isn't after the median time of the previous blocks")
+        );
+    }
+
+    #[test]
+    fn timestamp_below_the_median() {
+        let chain = chain();
+        assert_eq!(
+            check_timestamp(&block(Some(chain.tip()), 599), chain.tip(), &chain),
+            Err("timestamp isn't after the median time of the previous blocks")
+        );
+    }
+
+    #[test]
+    fn timestamp_after_the_median() {
+        // earlier than its parent, but after the median
+        let chain = chain();
+        assert_eq!(check_timestamp(&block(Some(chain.tip()), 601), chain.tip(), &chain), Ok(()));
+    }
+
+    #[test]
+    fn timestamp_beyond_the_drift() {
+        let chain = chain();
+        let future = Utc::now().timestamp() + max_drift() + 60;
+        assert_eq!(
+            check_timestamp(&block(Some(chain.tip()), future), chain.tip(), &chain),
+            Err("timestamp too far in the future")
+        );
+        let close = Utc::now().timestamp() + max_drift() - 60;
+        assert_eq!(check_timestamp(&block(Some(chain.tip()), close), chain.tip(), &chain), Ok(()));
+    }
+}
@@ -3,12 +3,15 @@ use std::sync::Arc;
 mod p2p;
 use auctions::peer::AuctionPeer;
 use p2p::{
+  identity,
   kad::KadNode,
   server
 };
 use std::env;
 use tokio::task;
+use ledger::amount::Amount;
 mod auctions;
+mod datadir;
 mod ledger;
 
 
@@ -21,12 +24,27 @@ fn read_terminal() -> String {
   line.trim().to_string()
 }
 
+// `key export <file>` or `key import <file>`, run instead of the node
+fn key_command(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+  let node_id = match (args.first().map(String::as_str), args.get(1)) {
+    (Some("export"), Some(file)) => identity::export_key(file)?,
+    (Some("import"), Some(file)) => identity::import_key(file)?,
+    _ => return Err("usage: key export <file> | key import <file>".into()),
+  };
+  println!("NodeID: {}", hex::encode(node_id.as_bytes()));
+  Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = env::args().collect();
+    if args.get(1).is_some_and(|command| command == "key") {
+      return key_command(&args[2..]);
+    }
     let address: String = args[1].split('\n').collect();    
     let is_bootstrap: String = args[2].split('\n').collect();
-    let node = Arc::new(KadNode::new(address.clone()));
+    let light = args.get(3).is_some_and(|mode| mode == "light");
+    let node = Arc::new(KadNode::new(address.clone(), light));
     let svnode = node.clone();
     let mut auctpeer = AuctionPeer::new(node.clone());
     let addr = address.parse().unwrap();
@@ -35,6 +53,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     });
 
     loop {
+      auctpeer.process_reorgs();
       println!("Insert command");
       let raw_command = read_terminal();
       let command: Vec<&str> = raw_command.split(' ').collect();
@@ -50,7 +69,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         "new_auction" => {
           assert_eq!(command.len(),4);
           let title = command[1].to_string();
-          let initial_price = command[2].parse::<f32>().unwrap();
+          let initial_price = match command[2].parse::<Amount>() {
+            Ok(price) => price,
+            Err(e) => { println!("{}", e); continue; },
+          };
           let duration = command[3].parse::<i64>().unwrap();
           auctpeer.new_auction(title,duration,initial_price).await;
         },
@@ -61,18 +83,52 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         "bid" => {
           assert_eq!(command.len(),3);
           let index = command[1].parse::<i32>().unwrap();
-          let bid = command[2].parse::<f32>().unwrap();
+          let bid = match command[2].parse::<Amount>() {
+            Ok(bid) => bid,
+            Err(e) => { println!("{}", e); continue; },
+          };
           let _ = auctpeer.bid_auction(index, bid).await;
         },
         "transaction" => {
-          assert_eq!(command.len(),2);
+          assert!(command.len() == 2 || command.len() == 3);
          let index = command[1].parse::<i32>().unwrap();
-         auctpeer.fulfill_transaction(index).await;
+         let fee = match command.get(2).map(|fee| fee.parse::<Amount>()).unwrap_or(Ok(Amount::ZERO)) {
+            Ok(fee) => fee,
+            Err(e) => { println!("{}", e); continue; },
+         };
+         auctpeer.fulfill_transaction(index, fee).await;
+        },
+        "prove_settlement" => {
+          assert_eq!(command.len(),2);
+          let index = command[1].parse::<i32>().unwrap();
+          auctpeer.prove_settlement(index).await;
+        },
+        "settlement" => {
+          assert_eq!(command.len(),2);
+          let index = command[1].parse::<i32>().unwrap();
+          auctpeer.find_settlement(index);
+        },
+        "snapshot" => {
+          assert_eq!(command.len(),2);
+          let height = command[1].parse::<u64>().unwrap();
+          auctpeer.snapshot(height);
+        },
+        "transactions" => {
+          assert!(command.len() <= 2);
+          auctpeer.account_transactions(command.get(1).copied());
+        },
+        "balance" => {
+          assert!(command.len() <= 2);
+          auctpeer.balance(command.get(1).copied());
         },
         "print_blockchain" => {
           assert_eq!(command.len(),1);
             auctpeer.client.print_blockchain()
         }
+        "mining_stats" => {
+          assert_eq!(command.len(),1);
+          auctpeer.client.print_mining_stats()
+        }
         "get_blockchain" => {
           assert_eq!(command.len(),1);
           auctpeer.client.req_blockchain().await?
@@ -1,18 +1,20 @@
-use std::{sync::{Arc, atomic::AtomicU64}, collections::HashSet, cmp::Ordering};
+use std::{sync::Arc, collections::HashSet, cmp::Ordering};
 use futures::future::join_all;
 use parking_lot::{RwLock, Mutex};
+use primitive_types::H256;
 use prost::Message;
 use tonic::Request;
-use std::sync::atomic::Ordering::SeqCst;
 
-use crate::{auctions::auction::AuctionGossip, ledger::block::Data};
+use crate::{auctions::auction::AuctionGossip, ledger::{amount::Amount, block::{Data, InclusionProof}}};
 
 use super::{
     node::Contact, 
     key::{NodeID, NodeValidator}, 
     kad::KadNode, 
+    replay::Stamp,
     signatures::Signer,
-    util::{gen_cookie, format_address, to_auction_data, encode_store, to_gossip_vec, grpc_transaction, encode_fvalue}, K_MAX_ENTRIES, kademlia::{kademlia_client::KademliaClient, FValueReq, Header, StoreReq, FNodeReq, Kcontact, self, PingM, BroadcastReq}
+    transport::connect,
+    util::{to_auction_data, encode_store, to_gossip_vec, grpc_transaction, encode_fvalue, try_to_data}, K_MAX_ENTRIES, kademlia::{FValueReq, Header, StoreReq, FNodeReq, Kcontact, self, PingM, BroadcastReq, CountersignReq}
 };
 
 const PARALLEL_LOOKUPS: i32 = 3;
@@ -40,18 +42,16 @@ struct FNodeManager {
     k_closest: Arc<Mutex<Vec<Contact>>>,
     nodes_to_visit: Arc<Mutex<Vec<Contact>>>,
     visited_nodes : Arc<RwLock<HashSet<NodeID>>>,  
-    timestamp: Arc<AtomicU64>,
     validator: NodeValidator,
     address: String,
 }
 
 impl FNodeManager {
-    pub fn new(k_closest: Vec<Contact>, nodes_to_visit: Vec<Contact>, visited_nodes: HashSet<NodeID>,timestamp: Arc<AtomicU64> ,validator: NodeValidator, address: String) -> Self {
+    pub fn new(k_closest: Vec<Contact>, nodes_to_visit: Vec<Contact>, visited_nodes: HashSet<NodeID>,validator: NodeValidator, address: String) -> Self {
         Self {
             k_closest : Arc::new(Mutex::new(k_closest)),
             nodes_to_visit : Arc::new(Mutex::new(nodes_to_visit)),
             visited_nodes : Arc::new(RwLock::new(visited_nodes)),
-            timestamp,
             validator : validator,
             address : address,
         }
@@ -93,10 +93,6 @@ impl FNodeManager {
     pub fn get_validator(&self) -> &NodeValidator {
         &self.validator
     }
-
-    pub fn increment(&self) -> u64 {
-        self.timestamp.fetch_add(1, SeqCst)
-    }
 }
 #[derive(Debug,Clone)]
 pub struct Client {
@@ -132,6 +128,46 @@ impl Client {
         self.node.print_blockchain()
     }
 
+    pub fn print_mining_stats(&self) {
+        self.node.print_mining_stats()
+    }
+
+    pub fn is_light(&self) -> bool {
+        self.node.is_light()
+    }
+
+    pub fn prove_settlement(&self, auction_id: H256) -> Option<InclusionProof> {
+        self.node.prove_settlement(auction_id)
+    }
+
+    pub fn verify_inclusion(&self, proof: &InclusionProof) -> Result<u64, &'static str> {
+        self.node.verify_inclusion(proof)
+    }
+
+    pub async fn request_proof(&self, auction_id: H256) -> Option<(InclusionProof, u64)> {
+        self.node.request_proof(auction_id).await
+    }
+
+    pub fn find_settlement(&self, auction_id: H256) -> Option<(u64, Data)> {
+        self.node.find_settlement(auction_id)
+    }
+
+    pub fn account_transactions(&self, account: NodeID) -> Vec<(u64, Data)> {
+        self.node.account_transactions(account)
+    }
+
+    pub fn snapshot(&self, height: u64) -> Result<String, &'static str> {
+        self.node.snapshot(height)
+    }
+
+    pub fn balance(&self, account: NodeID) -> (Amount, u64) {
+        self.node.balance(account)
+    }
+
+    pub fn record_bid(&self, auction_id: H256, amount: Amount) {
+        self.node.record_bid(auction_id, amount)
+    }
+
     pub async fn bootstrap(&self) -> Result<(), &str> {
         let boot_key = NodeID::from_vec(BOOT_ID.to_vec());
         self.node.insert(Contact::new(boot_key, BOOTSTRAP_IP.to_owned(), BOOTSTRAP_KEY.to_vec()));
@@ -179,7 +215,6 @@ impl Client {
         let find_nodes = FNodeManager::new(
             k_closest,nodes_to_visit,
             visited_nodes,
-            self.node.get_timestamp(),
             self.node.get_validator().clone(),
             self.node.address.clone()
         );
@@ -202,17 +237,20 @@ impl Client {
         
         let k_closest = self.send_fnode(key).await;
         for contact in k_closest {
-            let address = format_address(contact.address.clone());
-            let mut client = KademliaClient::connect(address.clone()).await.unwrap();  
-            let timestamp = self.node.increment();
-            let (hash,request_signature) = Signer::sign_strong_header_req(timestamp,contact.get_pubkey(),&self.node.address,key.as_bytes());
+            let mut client = match connect(self.node.get_validator(), &contact).await {
+                Ok(client) => client,
+                Err(_) => continue,
+            };
+            let stamp = Stamp::now();
+            let (hash,request_signature) = Signer::sign_strong_header_req(self.node.get_validator(),stamp,&self.node.address,key.as_bytes());
             let request = FValueReq {
                 header: Some( Header {
                     my_id: self.node.uid.as_bytes().to_owned(),
                     address : self.node.address.to_owned(),
                     pub_key: self.node.get_pubkey(),
                     nonce: self.node.get_nonce(),
-                    timestamp,
+                    timestamp: stamp.time,
+                    cookie: stamp.cookie,
                     signature: request_signature.clone(),
                 }),
                 target_id: key.as_bytes().to_owned(),
@@ -224,7 +262,7 @@ impl Client {
                     let header = response.header.unwrap();
                     let data = response.has_value.unwrap();
                     let databuf = encode_fvalue(&data, key);
-                    if let Ok(()) = Signer::validate_strong_rep(self.node.get_validator(),&header,&contact.address,&databuf,&hash) {
+                    if let Ok(()) = Signer::validate_strong_rep(&header,&contact,&databuf,&hash) {
                         match data {
                             kademlia::f_value_repl::HasValue::Node(_) => continue,
                             kademlia::f_value_repl::HasValue::Auction(val) =>{
@@ -240,19 +278,22 @@ impl Client {
     }
 
     async fn send_store(&self,key:NodeID, value: AuctionGossip, contact: Contact) -> Result<(),&'static str> {
-        let address = format_address(contact.address.clone());
-        let mut client = KademliaClient::connect(address.clone()).await.unwrap();  
+        let mut client = match connect(self.node.get_validator(), &contact).await {
+            Ok(client) => client,
+            Err(_) => return Err("Could not connect to the node"),
+        };
         let formated_value = to_auction_data(value);
-        let timestamp =  self.node.increment();
+        let stamp = Stamp::now();
         let databuf: Vec<u8> = encode_store(&formated_value,key);
-        let (hash,request_signature) = Signer::sign_strong_header_req(timestamp,contact.get_pubkey(),&self.node.address,&databuf);
+        let (hash,request_signature) = Signer::sign_strong_header_req(self.node.get_validator(),stamp,&self.node.address,&databuf);
         let request = StoreReq {
                 header: Some( Header {
                     my_id: self.node.uid.as_bytes().to_owned(),
                     address : self.node.address.to_owned(),
                     pub_key: self.node.get_pubkey(),
                     nonce: self.node.get_nonce(),
-                    timestamp,
+                    timestamp: stamp.time,
+                    cookie: stamp.cookie,
                     signature: request_signature.clone() ,
                 }),
                 target_id: key.as_bytes().to_owned(),
@@ -264,7 +305,7 @@ impl Client {
             Ok(res) => {
                 let res = res.into_inner();
                 let header = res.header.unwrap();
-                if let Ok(()) = Signer::validate_weak_rep(self.node.get_validator(),&header,&contact.address,&hash) {
+                if let Ok(()) = Signer::validate_weak_rep(&header,&contact,&hash) {
                     return Ok(());
                 }
                 Err("Failed to verify signature")
@@ -273,6 +314,57 @@ impl Client {
         }
     }
 
+    // signs a settlement as the seller, gets the buyer's counter-signature and broadcasts it
+    pub async fn settle(&self, mut data: Data) -> Result<(), &'static str> {
+        data.sign_as_seller(self.node.get_validator())?;
+        if data.get_buyer() == self.get_uid() {
+            data.countersign(self.node.get_validator())?;
+        } else {
+            data = self.request_countersign(data).await?;
+        }
+        self.broadcast_transaction(data).await;
+        Ok(())
+    }
+
+    async fn request_countersign(&self, data: Data) -> Result<Data, &'static str> {
+        let buyer = data.get_buyer();
+        let contact = match self.send_fnode(buyer).await.into_iter().find(|c| c.uid == buyer) {
+            Some(contact) => contact,
+            None => return Err("Could not find the buyer"),
+        };
+        let mut client = match connect(self.node.get_validator(), &contact).await {
+            Ok(client) => client,
+            Err(_) => return Err("Could not connect to the buyer"),
+        };
+        let transaction = grpc_transaction(data);
+        let stamp = Stamp::now();
+        let (hash,request_signature) = Signer::sign_strong_header_req(self.node.get_validator(),stamp,&self.node.address,&transaction.encode_to_vec());
+        let request = CountersignReq {
+                header: Some( Header {
+                    my_id: self.node.uid.as_bytes().to_owned(),
+                    address : self.node.address.to_owned(),
+                    pub_key: self.node.get_pubkey(),
+                    nonce: self.node.get_nonce(),
+                    timestamp: stamp.time,
+                    cookie: stamp.cookie,
+                    signature: request_signature.clone() ,
+                }),
+                transaction: Some(transaction),
+            };
+
+        match client.countersign(request).await {
+            Ok(res) => {
+                let res = res.into_inner();
+                let header = res.header.ok_or("Missing header")?;
+                Signer::validate_weak_rep(&header,&contact,&hash)?;
+                let signed = res.transaction.and_then(try_to_data).ok_or("Malformed transaction")?;
+                signed.verify_signatures()?;
+                Ok(signed)
+            },
+            Err(_) => Err("The buyer refused to counter-sign"),
+        }
+    }
+
     pub async fn broadcast_transaction(&self, data: Data) {
         let my_closest = self.node.lookup(self.get_uid());
         let timestamp = self.node.increment_broadcast();
@@ -280,7 +372,7 @@ impl Client {
         println!("Broadcasting block");
     
         for contact in my_closest {
-            let connection = KademliaClient::connect(format_address(contact.address)).await; 
+            let connection = connect(self.node.get_validator(), &contact).await; 
 
             match connection {
                 Ok(mut channel) => {
@@ -314,19 +406,19 @@ async fn a_lookup(key: NodeID, info: Arc<FNodeManager>) {
              continue
          }
 
-         let address = format_address(node.address.clone());
-         let remote = KademliaClient::connect(address.clone()).await;  
+         let remote = connect(info.get_validator(), &node).await;  
          match remote {
              Ok(mut remote) => {
-                let timestamp = info.increment();
-                let (hash,request_signature) = Signer::sign_weak_header_req(timestamp,node.get_pubkey(),&info.address);
+                let stamp = Stamp::now();
+                let (hash,request_signature) = Signer::sign_weak_header_req(info.get_validator(),stamp,&info.address);
                 let request = FNodeReq {
                     header: Some( Header {
                         my_id: info.get_uid(),
                         address : info.address.to_owned(),
                         pub_key: info.get_pubkey(),
                         nonce: info.get_nonce(),
-                        timestamp,
+                        timestamp: stamp.time,
+                        cookie: stamp.cookie,
                         signature: request_signature.clone(),
                     }),
                      target_id: key.as_bytes().to_owned(),
@@ -342,7 +434,7 @@ async fn a_lookup(key: NodeID, info: Arc<FNodeManager>) {
                          let data = response.nodes.unwrap();
                          let mut databuf = Vec::new();
                          let _ = data.encode(&mut databuf);
-                         if let Ok(()) = Signer::validate_strong_rep(info.get_validator(),&header,&node.address,&databuf,&hash) {
+                         if let Ok(()) = Signer::validate_strong_rep(&header,&node,&databuf,&hash) {
                             let closest_to_contact = contact_list(data.node);
                             let (lv,success):(Vec<Contact>,bool);
                             // limiting the range of the lock
@@ -414,29 +506,33 @@ fn insert_closest(k_closest:&mut Vec<Contact>, mut local_visit: Vec<Contact>,mut
     (local_visit,success)
 }
 
+// drops the contacts whose NodeID isn't derived from their public key
 pub fn contact_list(kcontact_list: Vec<Kcontact>) -> Vec<Contact> {
     let converter = |k: &Kcontact| {
-        Contact::new(
+        if k.uid.len() != 32 {
+            return None;
+        }
+        Some(Contact::new(
             NodeID::from_vec(k.uid.clone()),
             k.address.clone(),
             k.pub_key.clone()
-        )
+        ))
     };
-    kcontact_list.iter().map(|a| converter(a) ).collect()
+    kcontact_list.iter().filter_map(converter).filter(|c| c.is_verified()).collect()
 }
 
 pub async fn send_ping(my_address: &str,validator: &NodeValidator, contact: Contact) -> bool {
-    let address = format_address(contact.address.clone());
-    if let Ok(mut client) = KademliaClient::connect(address.clone()).await{  
-    let timestamp =  gen_cookie();
-    let (hash,request_signature) = Signer::sign_weak_header_req(timestamp,contact.get_pubkey(),my_address);
+    if let Ok(mut client) = connect(validator, &contact).await{  
+    let stamp = Stamp::now();
+    let (hash,request_signature) = Signer::sign_weak_header_req(validator,stamp,my_address);
     let request = PingM {
             header: Some( Header {
                 my_id: validator.get_nodeid().as_bytes().to_owned(),
                 address : my_address.to_owned(),
                 pub_key: validator.get_pubkey(),
                 nonce: validator.get_nonce(),
-                timestamp: timestamp,
+                timestamp: stamp.time,
+                cookie: stamp.cookie,
                 signature: request_signature.clone(),
             }),
         };
@@ -445,7 +541,7 @@ pub async fn send_ping(my_address: &str,validator: &NodeValidator, contact: Cont
         Ok(response) => {
             let res = response.into_inner();
             let header = res.header.unwrap();
-            if let Ok(()) = Signer::validate_weak_rep(validator,&header,&contact.address,&hash) {
+            if let Ok(()) = Signer::validate_weak_rep(&header,&contact,&hash) {
                 return true;
             } else {
                 return false;
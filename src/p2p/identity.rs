@@ -0,0 +1,226 @@
+use std::{env, fs, io::{self, Write}};
+
+use openssl::{
+    pkcs5::scrypt,
+    pkey::{PKey, Private},
+    rand::rand_bytes,
+    symm::{decrypt_aead, encrypt_aead, Cipher},
+};
+use primitive_types::H256;
+
+use crate::{datadir::data_path, ledger::storage::write_atomic};
+
+use super::{key::{node_id, verify_static_puzzle, NodeID}, scheme::Scheme};
+
+// the node's encrypted private key, in the data directory
+pub const KEYSTORE: &str = "keystore";
+// the node's PEM public key, written next to the keystore to share it, e.g. in an authority set
+pub const PUB_KEY: &str = "pub_key";
+// read instead of asking for the keystore passphrase
+pub const PASSPHRASE_VAR: &str = "AUCTIONS_PASSPHRASE";
+
+// RSA key of nodes that predate the keystore, moved into it on their first start
+const LEGACY_PRIV_KEY: &str = "priv_key";
+const LEGACY_PASSPHRASE: &str = " ";
+
+const VERSION: &str = "1";
+// scrypt with 2^15 blocks of 8 * 128 bytes, 32 MiB per derivation
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u64 = 8;
+const SCRYPT_P: u64 = 1;
+const SCRYPT_MAXMEM: u64 = 64 * 1024 * 1024;
+const SALT_LEN: usize = 16;
+const IV_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/* A private key encrypted with a passphrase. Stored as text, one "<key> <value>" per line:
+     version 1
+     node_id <hex>
+     kdf scrypt <log2 n> <r> <p> <salt hex>
+     cipher aes-256-gcm <iv hex>
+     key <PKCS#8 DER encrypted with the scrypt key, then the GCM tag, in hex>
+   The NodeID is in clear so the identity is known without the passphrase, and authenticated as
+   associated data so it can't be swapped for another one. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keystore {
+    pub node_id: NodeID,
+    log_n: u8,
+    r: u64,
+    p: u64,
+    salt: Vec<u8>,
+    iv: Vec<u8>,
+    sealed: Vec<u8>,
+}
+
+impl Keystore {
+    pub fn seal(key: &PKey<Private>, passphrase: &str) -> Result<Keystore, &'static str> {
+        let node_id = node_id(&key.public_key_to_pem().map_err(|_| "invalid key")?);
+        let mut salt = vec![0; SALT_LEN];
+        let mut iv = vec![0; IV_LEN];
+        rand_bytes(&mut salt).and_then(|_| rand_bytes(&mut iv)).map_err(|_| "could not generate the keystore salt")?;
+
+        let secret = derive_key(passphrase, &salt, SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P)?;
+        let der = key.private_key_to_pkcs8().map_err(|_| "invalid key")?;
+        let mut tag = [0; TAG_LEN];
+        let mut sealed = encrypt_aead(Cipher::aes_256_gcm(), &secret, Some(&iv), node_id.as_bytes(), &der, &mut tag)
+            .map_err(|_| "could not encrypt the key")?;
+        sealed.extend_from_slice(&tag);
+
+        Ok(Keystore { node_id, log_n: SCRYPT_LOG_N, r: SCRYPT_R, p: SCRYPT_P, salt, iv, sealed })
+    }
+
+    pub fn open(&self, passphrase: &str) -> Result<PKey<Private>, &'static str> {
+        let secret = derive_key(passphrase, &self.salt, self.log_n, self.r, self.p)?;
+        let (ciphertext, tag) = self.sealed.split_at(self.sealed.len() - TAG_LEN);
+        let der = decrypt_aead(Cipher::aes_256_gcm(), &secret, Some(&self.iv), self.node_id.as_bytes(), ciphertext, tag)
+            .map_err(|_| "wrong passphrase or corrupted keystore")?;
+        PKey::private_key_from_pkcs8(&der).map_err(|_| "invalid key in the keystore")
+    }
+
+    pub fn parse(contents: &str) -> Result<Keystore, &'static str> {
+        let (mut version, mut node_id, mut kdf, mut iv, mut sealed) = (None, None, None, None, None);
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["version", v] => version = Some(*v),
+                ["node_id", id] => {
+                    let id = hex::decode(id).ok().filter(|id| id.len() == 32).ok_or("invalid keystore node id")?;
+                    node_id = Some(NodeID::from_h256(H256::from_slice(&id)));
+                },
+                ["kdf", "scrypt", log_n, r, p, salt] => {
+                    let params = (log_n.parse::<u8>(), r.parse::<u64>(), p.parse::<u64>(), hex::decode(salt));
+                    match params {
+                        (Ok(log_n), Ok(r), Ok(p), Ok(salt)) if log_n < 64 => kdf = Some((log_n, r, p, salt)),
+                        _ => return Err("invalid keystore kdf"),
+                    }
+                },
+                ["cipher", "aes-256-gcm", v] => {
+                    iv = Some(hex::decode(v).ok().filter(|v| v.len() == IV_LEN).ok_or("invalid keystore cipher")?);
+                },
+                ["key", key] => {
+                    sealed = Some(hex::decode(key).ok().filter(|key| key.len() > TAG_LEN).ok_or("invalid keystore key")?);
+                },
+                _ => return Err("invalid keystore line"),
+            }
+        }
+        if version != Some(VERSION) {
+            return Err("unsupported keystore version");
+        }
+        match (node_id, kdf, iv, sealed) {
+            (Some(node_id), Some((log_n, r, p, salt)), Some(iv), Some(sealed)) => Ok(Keystore { node_id, log_n, r, p, salt, iv, sealed }),
+            _ => Err("incomplete keystore"),
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        format!(
+            "version {}\nnode_id {}\nkdf scrypt {} {} {} {}\ncipher aes-256-gcm {}\nkey {}\n",
+            VERSION,
+            hex::encode(self.node_id.as_bytes()),
+            self.log_n, self.r, self.p, hex::encode(&self.salt),
+            hex::encode(&self.iv),
+            hex::encode(&self.sealed),
+        )
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], log_n: u8, r: u64, p: u64) -> Result<[u8; 32], &'static str> {
+    let mut secret = [0; 32];
+    scrypt(passphrase.as_bytes(), salt, 1 << log_n, r, p, SCRYPT_MAXMEM, &mut secret).map_err(|_| "invalid keystore kdf")?;
+    Ok(secret)
+}
+
+/* The node's key: the one in the keystore, the key of a node that predates the keystore, or a
+   new Ed25519 key. A keystore that can't be opened stops the node rather than starting it under
+   another identity. */
+pub fn load_identity() -> PKey<Private> {
+    let path = data_path(KEYSTORE);
+    match fs::read_to_string(&path) {
+        Ok(contents) => {
+            let keystore = Keystore::parse(&contents).unwrap_or_else(|e| panic!("{} in {}", e, path));
+            keystore.open(&passphrase("Keystore passphrase")).unwrap_or_else(|e| panic!("{}", e))
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let key = legacy_key().unwrap_or_else(generate_key);
+            store(&key, &passphrase("New keystore passphrase")).unwrap_or_else(|e| panic!("{}", e));
+            key
+        },
+        // a keystore that exists but can't be read mustn't be replaced by a new one
+        Err(e) => panic!("Could not read the keystore {}: {}", path, e),
+    }
+}
+
+// new Ed25519 keys until the NodeID solves the static puzzle
+pub fn generate_key() -> PKey<Private> {
+    loop {
+        let key = PKey::generate_ed25519().unwrap();
+        if verify_static_puzzle(node_id(&key.public_key_to_pem().unwrap())) {
+            return key;
+        }
+    }
+}
+
+// the RSA key earlier versions kept under a fixed passphrase, if it can still be used as an identity
+fn legacy_key() -> Option<PKey<Private>> {
+    let path = data_path(LEGACY_PRIV_KEY);
+    let key = PKey::private_key_from_pem_passphrase(&fs::read(&path).ok()?, LEGACY_PASSPHRASE.as_bytes()).ok()?;
+    if !usable(&key) {
+        return None;
+    }
+    println!("Moving the key in {} to the keystore, the file can be deleted afterwards", path);
+    Some(key)
+}
+
+fn usable(key: &PKey<Private>) -> bool {
+    Scheme::of(key).is_some() && key.public_key_to_pem().is_ok_and(|pem| verify_static_puzzle(node_id(&pem)))
+}
+
+fn store(key: &PKey<Private>, passphrase: &str) -> Result<(), &'static str> {
+    let keystore = Keystore::seal(key, passphrase)?;
+    write_atomic(&data_path(KEYSTORE), keystore.encode().as_bytes()).map_err(|_| "could not write the keystore")?;
+    let pub_key = key.public_key_to_pem().map_err(|_| "invalid key")?;
+    fs::write(data_path(PUB_KEY), pub_key).map_err(|_| "could not write the public key")
+}
+
+// the environment variable if it's set, otherwise asks on the terminal
+pub fn passphrase(prompt: &str) -> String {
+    if let Ok(passphrase) = env::var(PASSPHRASE_VAR) {
+        return passphrase;
+    }
+    print!("{}: ", prompt);
+    io::stdout().flush().unwrap();
+    let mut line = String::new();
+    io::stdin().read_line(&mut line).expect("Error: Could not read a line");
+    line.trim_end_matches(['\r', '\n']).to_owned()
+}
+
+/* Makes a PEM private key the node's identity, the file is decrypted with the passphrase the new
+   keystore gets (or isn't encrypted). Refuses to replace an existing keystore. */
+pub fn import_key(file: &str) -> Result<NodeID, &'static str> {
+    match fs::metadata(data_path(KEYSTORE)) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Ok(_) => return Err("the data directory already has a keystore, move it away first"),
+        Err(_) => return Err("could not check for an existing keystore"),
+    }
+    let pem = fs::read(file).map_err(|_| "could not read the key file")?;
+    let passphrase = passphrase("Keystore passphrase");
+    let key = PKey::private_key_from_pem_passphrase(&pem, passphrase.as_bytes()).map_err(|_| "invalid key file or wrong passphrase")?;
+    if !usable(&key) {
+        return Err("unsupported key type, or its NodeID doesn't solve the static puzzle");
+    }
+    store(&key, &passphrase)?;
+    Ok(node_id(&key.public_key_to_pem().unwrap()))
+}
+
+// writes the node's key as a PKCS#8 PEM, encrypted with the keystore passphrase
+pub fn export_key(file: &str) -> Result<NodeID, &'static str> {
+    let contents = fs::read_to_string(data_path(KEYSTORE)).map_err(|_| "no keystore in the data directory")?;
+    let keystore = Keystore::parse(&contents)?;
+    let passphrase = passphrase("Keystore passphrase");
+    let pem = keystore
+        .open(&passphrase)?
+        .private_key_to_pem_pkcs8_passphrase(Cipher::aes_256_cbc(), passphrase.as_bytes())
+        .map_err(|_| "could not encode the key")?;
+    fs::write(file, pem).map_err(|_| "could not write the key file")?;
+    Ok(keystore.node_id)
+}
@@ -1,16 +1,21 @@
-use std::{collections::HashMap, sync::{atomic::AtomicU64, Arc}};
+use std::{collections::HashMap, sync::atomic::AtomicU64};
 
 use chrono::{DateTime, Utc};
 use parking_lot::RwLock;
+use primitive_types::H256;
+use tokio::sync::broadcast;
+use futures::future::join_all;
 use tonic::Request;
 use std::sync::atomic::Ordering::{SeqCst,Acquire};
-use crate::{auctions::auction::AuctionGossip, ledger::{block::{Chain, Block, Data}, miner::Miner}};
+use crate::{auctions::auction::AuctionGossip, ledger::{amount::Amount, block::{Block, Data, InclusionProof, Reorg}, genesis::genesis, consensus::{load_consensus, Consensus}, miner::Miner, sync::MAX_HEADERS}};
 
 use super::{
     key::{NodeValidator, NodeID}, 
     rtable::Rtable, node::Contact, 
-    kademlia::{kademlia_client::KademliaClient, Header, StoreReq, BroadcastReq, PingM}, 
-    util::{format_address, encode_store, to_auction_data, grpc_block, to_block}, 
+    replay::{ReplayGuard, Stamp},
+    kademlia::{Header, StoreReq, BroadcastReq, HeadersReq, BlocksReq, ProofReq}, 
+    transport::connect,
+    util::{encode_store, to_auction_data, grpc_block, try_to_block, try_to_header, try_to_proof},
     signatures::Signer
 };
 
@@ -19,28 +24,40 @@ pub struct KadNode {
     pub uid: NodeID,
     pub address: String,
     pub join_date: DateTime<Utc>,
-    timestamp: Arc<AtomicU64>,
     last_broadcast: AtomicU64,
+    // stamps of the requests peers sent us recently
+    replays: ReplayGuard,
     validator: NodeValidator,
     rtable: RwLock<Rtable>,
     data_store: RwLock<HashMap<NodeID,Vec<AuctionGossip>>>,
-    miner: Miner
+    // our latest bid on every auction, the only settlements we counter-sign
+    bids: RwLock<HashMap<H256,Amount>>,
+    miner: Miner<Box<dyn Consensus>>
 }
 
 impl KadNode {
-    pub fn new(addr: String) -> KadNode {
+    // a light node only syncs the headers and asks full nodes for proofs of its settlements
+    pub fn new(addr: String, light: bool) -> KadNode {
         let valid = NodeValidator::new();
+        let consensus = load_consensus(&valid);
+        let beneficiary = valid.get_nodeid();
         let date = Utc::now();
+        let miner = if light {
+            Miner::new_light(consensus, beneficiary)
+        } else {
+            Miner::new(consensus, beneficiary)
+        };
        KadNode {
             uid: valid.get_nodeid(),
             address: addr,
             rtable: RwLock::new(Rtable::new()),
             join_date: date,
-            timestamp: Arc::new(AtomicU64::new(0)),
             last_broadcast: AtomicU64::new(0),
+            replays: ReplayGuard::new(),
             data_store: RwLock::new(HashMap::new()),
+            bids: RwLock::new(HashMap::new()),
             validator : valid,
-            miner: Miner::new(), 
+            miner,
         }
     }
 
@@ -57,7 +74,12 @@ impl KadNode {
     }
 */
 
+    // contacts that can't prove their NodeID never make it into the routing table
     pub fn insert(&self,contact:Contact) {
+        if !contact.is_verified() {
+            println!("Refusing contact {} with an unverifiable id", contact.address);
+            return;
+        }
         self.rtable.write().insert(&self.address,contact, &self.validator)
     }
 
@@ -73,6 +95,10 @@ impl KadNode {
         self.miner.print_blockchain()
     }
 
+    pub fn print_mining_stats(&self) {
+        self.miner.print_mining_stats()
+    }
+
     pub fn store_value(&self, key: NodeID, value: AuctionGossip) -> Result<(), &'static str> {
         let mut keys = self.get_store_keys(&value);
         if !keys.contains(&key) {
@@ -106,6 +132,21 @@ impl KadNode {
         Ok(())
      }
 
+    pub fn record_bid(&self, auction_id: H256, amount: Amount) {
+        self.bids.write().insert(auction_id, amount);
+    }
+
+    // counter-signs a settlement as its buyer, only for the amount we bid
+    pub fn countersign(&self, mut data: Data) -> Result<Data, &'static str> {
+        match self.bids.read().get(&data.get_auction_id()) {
+            Some(amount) if *amount == data.get_amount() => (),
+            Some(_) => return Err("amount doesn't match our bid"),
+            None => return Err("no bid on this auction"),
+        }
+        data.countersign(&self.validator)?;
+        Ok(data)
+    }
+
     pub fn retrieve(&self, key: NodeID) -> Option<Vec<AuctionGossip>> {
         if let Some(value) = self.data_store.read().get(&key) {
             Some(value.clone())
@@ -130,18 +171,6 @@ impl KadNode {
         self.uid.clone()
     }
 
-    pub fn get_timestamp(&self) -> Arc<AtomicU64> {
-        self.timestamp.clone()
-    }
-
-    pub fn get_chain(&self) -> Chain {
-        self.miner.get_chain()
-    }
-    
-    pub fn increment(&self) -> u64 {
-        self.timestamp.fetch_add(1, SeqCst)
-    }
-
     
     pub fn increment_broadcast(&self) -> u64 {
         self.last_broadcast.fetch_add(1, SeqCst)
@@ -165,26 +194,52 @@ impl KadNode {
         }
     }
 
-    // syncronizes timestamp
-    pub fn compare(&self, other: u64) -> u64 {
-        loop {
-            let cur = self.timestamp.load(SeqCst);
-            if cur >= other {
-               return cur;
-            } else {
-                match self.timestamp.compare_exchange(cur, other+1, SeqCst, Acquire) {
-                    Ok(value) => return value,
-                    Err(value) => {
-                        if value >= other {
-                           return value;
-                        }
-                    },
-                }
-            }
-        }
+    // refuses a request the peer already sent, or one signed too far from our clock
+    pub fn check_replay(&self, peer: NodeID, stamp: Stamp) -> Result<(), &'static str> {
+        self.replays.check(peer, stamp)
+    }
+
+    pub fn is_light(&self) -> bool {
+        self.miner.is_light()
+    }
+
+    pub fn prove_settlement(&self, auction_id: H256) -> Option<InclusionProof> {
+        self.miner.prove_settlement(auction_id)
+    }
+
+    pub fn verify_inclusion(&self, proof: &InclusionProof) -> Result<u64, &'static str> {
+        self.miner.verify_inclusion(proof)
+    }
+
+    pub fn find_settlement(&self, auction_id: H256) -> Option<(u64, Data)> {
+        self.miner.find_settlement(auction_id)
+    }
+
+    pub fn account_transactions(&self, account: NodeID) -> Vec<(u64, Data)> {
+        self.miner.account_transactions(account)
+    }
+
+    pub fn snapshot(&self, height: u64) -> Result<String, &'static str> {
+        self.miner.snapshot(height)
+    }
+
+    pub fn headers_after(&self, locator: &[H256]) -> Vec<Block> {
+        self.miner.headers_after(locator)
+    }
+
+    pub fn get_blocks(&self, hashes: &[H256]) -> Vec<Block> {
+        self.miner.get_blocks(hashes)
     }
 
-    pub fn store_transaction(&self, t: Data) {
+    pub fn subscribe_reorgs(&self) -> broadcast::Receiver<Reorg> {
+        self.miner.subscribe_reorgs()
+    }
+
+    pub fn balance(&self, account: NodeID) -> (Amount, u64) {
+        self.miner.balance(account)
+    }
+
+    pub fn store_transaction(&self, t: Data) -> Result<(), &'static str> {
         self.miner.store_transaction(t)
     }
 
@@ -219,19 +274,22 @@ impl KadNode {
     }
 
     async fn send_publish(&self, target_key: NodeID, value:AuctionGossip, contact: Contact) -> Result<(),&'static str>  {
-        let address = format_address(contact.address.clone());
-        let mut client = KademliaClient::connect(address.clone()).await.unwrap();  
+        let mut client = match connect(&self.validator, &contact).await {
+            Ok(client) => client,
+            Err(_) => return Err("Could not connect to the node"),
+        };
         let formated_value = to_auction_data(value);
-        let timestamp =  self.increment();
+        let stamp = Stamp::now();
         let databuf: Vec<u8> = encode_store(&formated_value,target_key);
-        let (hash,request_signature) = Signer::sign_strong_header_req(timestamp,contact.get_pubkey(),&self.address,&databuf);
+        let (hash,request_signature) = Signer::sign_strong_header_req(&self.validator,stamp,&self.address,&databuf);
         let request = StoreReq {
                 header: Some( Header {
                     my_id: self.uid.as_bytes().to_owned(),
                     address : self.address.to_owned(),
                     pub_key: self.get_pubkey(),
                     nonce: self.get_nonce(),
-                    timestamp,
+                    timestamp: stamp.time,
+                    cookie: stamp.cookie,
                     signature: request_signature.clone() ,
                 }),
                 target_id: target_key.as_bytes().to_owned(),
@@ -243,7 +301,7 @@ impl KadNode {
             Ok(res) => {
                 let res = res.into_inner();
                 let header = res.header.unwrap();
-                if let Ok(()) = Signer::validate_weak_rep(self.get_validator(),&header,&contact.address,&hash) {
+                if let Ok(()) = Signer::validate_weak_rep(&header,&contact,&hash) {
                     return Ok(());
                 }
                 Err("Failed to verify signature")
@@ -253,14 +311,21 @@ impl KadNode {
     }
 
     pub async fn mine_and_broadcast(&self) {
-        let block = self.miner.mine();
+        // sealing runs on the mining threads, this worker is handed off while it waits
+        let block = match tokio::task::block_in_place(|| self.miner.mine()) {
+            Ok(block) => block,
+            Err(e) => {
+                println!("Not mining: {}", e);
+                return;
+            },
+        };
         let my_closest = self.lookup(self.uid);
         let timestamp = self.increment_broadcast();
         let data = grpc_block(block.clone());
         
 
         for contact in my_closest {
-            let connection = KademliaClient::connect(format_address(contact.address)).await; 
+            let connection = connect(&self.validator, &contact).await; 
 
             match connection {
                 Ok(mut channel) => {
@@ -275,47 +340,186 @@ impl KadNode {
         }
     }
 
+    /* Headers-first sync with the k closest peers: they get a locator of our main chain and reply
+       with the headers after it, the best valid branch is kept and its bodies are downloaded from
+       all the peers that sent headers in parallel. Progress survives failed rounds, so calling
+       this again resumes with the bodies still missing. */
     pub async fn request_chain(&self)  -> Result<(), Box<dyn std::error::Error>> {
-        let timestamp =  self.increment();
-        let closest = self.lookup(self.uid);
-        let mut chains:  Vec<Vec<Block>> = Vec::new();
-        for close in closest {
-            let client = KademliaClient::connect(format_address(close.address.clone())).await;
-            let mut chain : Vec<Block> = Vec::new();
-            match client {
-                Ok(mut channel) => {
-                    let (_,request_signature) = Signer::sign_weak_header_req(timestamp,close.get_pubkey(),&self.address);
-                    let request = PingM {
-                        header: Some( Header {
-                            my_id: self.validator.get_nodeid().as_bytes().to_owned(),
-                            address : self.address.to_owned(),
-                            pub_key: self.validator.get_pubkey(),
-                            nonce: self.validator.get_nonce(),
-                            timestamp: timestamp.clone(),
-                            signature: request_signature.clone(),
-                        }),
-                    };
-        
-                    let mut stream = channel
-                        .req_chain(Request::new(request))
-                        .await?
-                        .into_inner();
-                  
-                    
-                    while let Some(block) = stream.message().await? {
-                        chain.push(to_block(block));
+        loop {
+            let locator = self.miner.locator();
+            let mut peers = Vec::new();
+            // a peer that sent a full reply has more headers after those
+            let mut more = false;
+            for contact in self.lookup(self.uid) {
+                let headers = match self.request_headers(&contact, &locator).await {
+                    Some(headers) if !headers.is_empty() => headers,
+                    _ => continue,
+                };
+                let full = headers.len() == MAX_HEADERS;
+                match self.miner.sync_headers(headers) {
+                    Ok(kept) => {
+                        more |= kept && full;
+                        peers.push(contact);
+                    },
+                    Err(e) => println!("rejected headers from {:?}: {}", contact.address, e),
+                }
+            }
+
+            let missing = self.miner.missing_bodies();
+            if !missing.is_empty() && !peers.is_empty() {
+                let mut shares = vec![Vec::new(); peers.len()];
+                for (i, hash) in missing.into_iter().enumerate() {
+                    shares[i % peers.len()].push(hash);
+                }
+                let requests = peers.iter().zip(shares).map(|(contact, hashes)| self.request_blocks(contact, hashes));
+                for (contact, blocks) in peers.iter().zip(join_all(requests).await) {
+                    for block in blocks {
+                        if let Err(e) = self.miner.sync_body(block) {
+                            println!("node {:?} sent an invalid block: {}", &contact.address, e);
+                        }
                     }
+                }
+            }
+
+            match self.miner.connect_synced() {
+                Ok(true) if more => continue,
+                Ok(true) => return Ok(()),
+                Ok(false) => {
+                    println!("sync paused, {} blocks still missing", self.miner.missing_bodies().len());
+                    return Ok(());
+                },
+                Err(e) => {
+                    println!("abandoned sync, invalid block: {}", e);
+                    return Ok(());
                 },
-                Err(_) => continue,
             }
+        }
+    }
 
-            if !chain.is_empty() {
-                chains.push(chain);
+    async fn request_headers(&self, contact: &Contact, locator: &[H256]) -> Option<Vec<Block>> {
+        let mut channel = connect(&self.validator, contact).await.ok()?;
+        let stamp = Stamp::now();
+        let (_,request_signature) = Signer::sign_weak_header_req(&self.validator,stamp,&self.address);
+        let request = HeadersReq {
+            header: Some( Header {
+                my_id: self.validator.get_nodeid().as_bytes().to_owned(),
+                address : self.address.to_owned(),
+                pub_key: self.validator.get_pubkey(),
+                nonce: self.validator.get_nonce(),
+                timestamp: stamp.time,
+                cookie: stamp.cookie,
+                signature: request_signature,
+            }),
+            locator: locator.iter().map(|hash| hash.as_bytes().to_owned()).collect(),
+            genesis: genesis().hash.as_bytes().to_owned(),
+        };
+
+        let reply = match channel.get_headers(Request::new(request)).await {
+            Ok(response) => response.into_inner(),
+            Err(e) => {
+                println!("node {:?} didn't send its headers: {}", &contact.address, e);
+                return None;
+            },
+        };
+        if reply.genesis != genesis().hash.as_bytes() {
+            println!("node {:?} is on a different network", &contact.address);
+            return None;
+        }
+        let headers = reply.headers;
+        let headers: Option<Vec<Block>> = headers.into_iter().take(MAX_HEADERS).map(try_to_header).collect();
+        if headers.is_none() {
+            println!("node {:?} sent a malformed header", &contact.address);
+        }
+        headers
+    }
+
+    /* Asks the closest peers for a proof that the auction's settlement is in our main chain, which
+       is all a light client can check. Returns the first proof that verifies against our headers
+       and its block's confirmations. */
+    pub async fn request_proof(&self, auction_id: H256) -> Option<(InclusionProof, u64)> {
+        for contact in self.lookup(self.uid) {
+            let mut channel = match connect(&self.validator, &contact).await {
+                Ok(channel) => channel,
+                Err(_) => continue,
+            };
+            let stamp = Stamp::now();
+            let (_,request_signature) = Signer::sign_weak_header_req(&self.validator,stamp,&self.address);
+            let request = ProofReq {
+                header: Some( Header {
+                    my_id: self.validator.get_nodeid().as_bytes().to_owned(),
+                    address : self.address.to_owned(),
+                    pub_key: self.validator.get_pubkey(),
+                    nonce: self.validator.get_nonce(),
+                    timestamp: stamp.time,
+                    cookie: stamp.cookie,
+                    signature: request_signature,
+                }),
+                auction_id: auction_id.as_bytes().to_owned(),
+            };
+
+            let proof = match channel.get_proof(Request::new(request)).await {
+                Ok(response) => response.into_inner().proof,
+                Err(e) => {
+                    println!("node {:?} didn't send a proof: {}", &contact.address, e);
+                    continue;
+                },
+            };
+            let proof = match proof.map(try_to_proof) {
+                Some(Some(proof)) if proof.transaction.get_auction_id() == auction_id => proof,
+                Some(_) => {
+                    println!("node {:?} sent a malformed proof", &contact.address);
+                    continue;
+                },
+                None => continue,
+            };
+            match self.verify_inclusion(&proof) {
+                Ok(confirmations) => return Some((proof, confirmations)),
+                Err(e) => println!("node {:?} sent an invalid proof: {}", &contact.address, e),
             }
         }
+        None
+    }
 
-        self.miner.choose_chain(chains);
-        Ok(())
+    // the blocks the peer sent before failing, if it does
+    async fn request_blocks(&self, contact: &Contact, hashes: Vec<H256>) -> Vec<Block> {
+        let mut blocks = Vec::new();
+        let mut channel = match connect(&self.validator, contact).await {
+            Ok(channel) => channel,
+            Err(_) => return blocks,
+        };
+        let stamp = Stamp::now();
+        let (_,request_signature) = Signer::sign_weak_header_req(&self.validator,stamp,&self.address);
+        let request = BlocksReq {
+            header: Some( Header {
+                my_id: self.validator.get_nodeid().as_bytes().to_owned(),
+                address : self.address.to_owned(),
+                pub_key: self.validator.get_pubkey(),
+                nonce: self.validator.get_nonce(),
+                timestamp: stamp.time,
+                cookie: stamp.cookie,
+                signature: request_signature,
+            }),
+            hashes: hashes.iter().map(|hash| hash.as_bytes().to_owned()).collect(),
+        };
+
+        let mut stream = match channel.get_blocks(Request::new(request)).await {
+            Ok(response) => response.into_inner(),
+            Err(e) => {
+                println!("node {:?} didn't send blocks: {}", &contact.address, e);
+                return blocks;
+            },
+        };
+        while let Ok(Some(block)) = stream.message().await {
+            match try_to_block(block) {
+                Some(block) if blocks.len() < hashes.len() => blocks.push(block),
+                Some(_) => break,
+                None => {
+                    println!("node {:?} sent a malformed block", &contact.address);
+                    break;
+                },
+            }
+        }
+        blocks
     }
 
     pub fn validate_blocks(&self) -> Result<(), &'static str>{
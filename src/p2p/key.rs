@@ -1,42 +1,48 @@
-use std::{fmt, fs::{File, self}, io::{BufReader, Read}};
+use std::fmt;
 
-use openssl::{pkey::{Public, Private}, rsa::{Rsa, Padding}, sha::Sha256, symm::Cipher};
+use openssl::{pkey::{PKey, PKeyRef, Public, Private}, sha::Sha256};
 use primitive_types::H256;
 use rand::Rng;
 
-use super::{C1, C2};
+use super::{identity::load_identity, scheme::Scheme, C1, C2};
 
 #[derive(Clone)]
 pub struct NodeValidator {
     node_id: NodeID,
-    pub_key: Rsa<Public>,
+    pub_key: PKey<Public>,
     nonce: u64,
-    priv_key: Rsa<Private>,
+    priv_key: PKey<Private>,
+    scheme: Scheme,
 }
     
 impl NodeValidator {
-    /* Generates a random id to use as the kademlia ID */
+    /* Loads the node's key from the keystore, its hash is the kademlia ID */
     pub fn new() -> NodeValidator {
-        let (k,pub_key,priv_key) = get_keypair();
-        let node_id = NodeID(k);
+        let priv_key = load_identity();
+        let pub_key = priv_key.public_key_to_pem().unwrap();
+        let node_id = node_id(&pub_key);
         let nonce = solve_puzzle(node_id);     
-        println!("keybytes: {:?}", k.as_bytes());
-        println!("key: {:?}", k);
+        println!("keybytes: {:?}", node_id.as_bytes());
+        println!("key: {:?}", node_id.0);
         println!("NONCE: {:?}", nonce);
 
         NodeValidator {
             node_id: node_id,
-            pub_key: Rsa::public_key_from_pem(&pub_key).unwrap(),
+            pub_key: PKey::public_key_from_pem(&pub_key).unwrap(),
             nonce: nonce,
-            priv_key: Rsa::private_key_from_pem_passphrase(&priv_key, " ".as_bytes()).unwrap(),
-
+            scheme: Scheme::of(&priv_key).expect("Unsupported node key type"),
+            priv_key,
         }   
     }
 
-    pub fn decrypt(&self, encrypted: &[u8]) -> Vec<u8> {
-        let mut buf: Vec<u8> = vec![0; self.priv_key.size() as usize];
-        self.priv_key.private_decrypt(encrypted, &mut buf, Padding::PKCS1).unwrap();
-        buf
+    // signature with the node's private key, checked with verify_signature against its public key
+    pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+        self.scheme.sign(&self.priv_key, data).unwrap()
+    }
+
+    // for the TLS handshake, everything else goes through sign
+    pub(crate) fn get_privkey(&self) -> &PKeyRef<Private> {
+        &self.priv_key
     }
 
     pub fn get_pubkey(&self) -> Vec<u8> {
@@ -57,8 +63,8 @@ impl fmt::Debug for NodeValidator {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("NodeValidator")
          .field("nonce",&self.nonce)
+         .field("scheme", &self.scheme)
          .field("pub_key", &self.pub_key)
-         .field("priv_key", &self.priv_key)
          .finish()
     }
 }
@@ -129,6 +135,21 @@ fn solve_puzzle(node_id: NodeID) -> u64 {
     }
 }
 
+// NodeIDs are the hash of the node's PEM public key
+pub fn node_id(pub_key: &[u8]) -> NodeID {
+    let mut hasher = Sha256::new();
+    hasher.update(pub_key);
+    NodeID(H256::from(hasher.finish()))
+}
+
+// checks a signature made by NodeValidator::sign against a PEM encoded public key, with the key's scheme
+pub fn verify_signature(pub_key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    match PKey::public_key_from_pem(pub_key) {
+        Ok(key) => Scheme::of(&key).is_some_and(|scheme| scheme.verify(&key, data, signature)),
+        Err(_) => false,
+    }
+}
+
 pub fn verify_puzzle(node_id: NodeID, nonce: u64) -> bool {
     let mut hasher = Sha256::new();
     hasher.update(node_id.as_bytes());
@@ -141,57 +162,18 @@ pub fn verify_puzzle(node_id: NodeID, nonce: u64) -> bool {
     false
 }
 
-fn get_keypair() -> (H256,Vec<u8>,Vec<u8>) {
-    let pub_location = "config/pub_key";
-    let priv_location = "config/priv_key";
-    match (File::open(pub_location),File::open(priv_location)) {
-        (Ok(fpub),Ok(fpriv)) => {
-            let mut hasher = Sha256::new();
-            let mut pubreader = BufReader::new(fpub);
-            let mut pubk: String = String::new();
-            let mut privreader = BufReader::new(fpriv);
-            let mut privk: String = String::new();
-            pubreader.read_to_string(&mut pubk).unwrap();
-            privreader.read_to_string(&mut privk).unwrap();
-            hasher.update(pubk.as_bytes());
-            let id_key = hasher.finish();
-            hasher = Sha256::new();
-            hasher.update(&id_key);
-            if leading_zeros(&hasher.finish()) < C1 {
-                //if the node id doesn't solve the static puzzle we create a new pair
-                return gen_keypair(pub_location,priv_location);
-            }
-            (H256::from_slice(&id_key),pubk.as_bytes().to_owned(),privk.as_bytes().to_owned())
-        },  
-        _ => gen_keypair(pub_location,priv_location),
-    }
+// S/Kademlia static puzzle, the hash of the NodeID needs C1 leading zeros
+pub fn verify_static_puzzle(node_id: NodeID) -> bool {
+    let mut hasher = Sha256::new();
+    hasher.update(node_id.as_bytes());
+    leading_zeros(&hasher.finish()) >= C1
 }
 
-fn gen_keypair(pub_location: &str, priv_location: &str) -> (H256,Vec<u8>,Vec<u8>) {
-    let passphrase = " ";
-    let mut private_key: Vec<u8>;
-    let mut public_key: Vec<u8>;
-    let mut hashed_key;
-    loop {
-        let mut hasher = Sha256::new();
-        let rsa = Rsa::generate(2048).unwrap();
-        private_key = rsa.private_key_to_pem_passphrase(Cipher::aes_128_cbc(), passphrase.as_bytes()).unwrap();
-        public_key= rsa.public_key_to_pem().unwrap();
-        hasher.update(&public_key);
-        hashed_key = hasher.finish();
-        hasher = Sha256::new();
-        hasher.update(&hashed_key);
-        if leading_zeros(&hasher.finish()) >= C1 {
-            break;
-        }
-    }
-    fs::write(pub_location,String::from_utf8(public_key.clone()).unwrap()).unwrap();
-    fs::write(priv_location, String::from_utf8(private_key.clone()).unwrap()).unwrap();
-    let id = H256::from_slice(&hashed_key);
-    (id,public_key,private_key)
+// a NodeID can only be used with the key it was derived from, and only if it solves the static puzzle
+pub fn verify_node_id(node_id: NodeID, pub_key: &[u8]) -> bool {
+    self::node_id(pub_key) == node_id && verify_static_puzzle(node_id)
 }
 
-
 pub fn leading_zeros(bytes: &[u8]) -> u32{
     let mut zeros = 0;
     for byte in bytes {
@@ -203,11 +185,3 @@ pub fn leading_zeros(bytes: &[u8]) -> u32{
     }
     zeros
 }
-
-pub fn encrypt_message(publ_key: &[u8], message: &[u8]) -> Vec<u8> {
-    let pub_key = Rsa::public_key_from_pem(publ_key).unwrap();
-    let mut buf: Vec<u8> = vec![0; pub_key.size() as usize];
-    pub_key.public_encrypt(message, &mut buf, Padding::PKCS1).unwrap();
-
-    buf
-}
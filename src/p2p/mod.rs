@@ -1,12 +1,16 @@
 pub mod client;
+pub mod identity;
 pub mod kad;
 pub mod key;
 pub mod node;
 pub mod protocol;
+pub mod replay;
 pub mod rtable;
+pub mod scheme;
 pub mod server;
+pub mod transport;
 mod signatures;
-mod util;
+pub(crate) mod util;
 mod kademlia {
     tonic::include_proto!("kadproto");
 }
@@ -4,7 +4,7 @@ use chrono::{DateTime, Utc};
 use futures::executor;
 use to_binary::BinaryString;
 
-use super::{key::{NodeID, NodeValidator}, K_MAX_ENTRIES, kademlia::Kcontact, client::send_ping};
+use super::{key::{verify_node_id, NodeID, NodeValidator}, K_MAX_ENTRIES, kademlia::Kcontact, client::send_ping};
 
 
 
@@ -32,10 +32,6 @@ impl Contact {
         }
     }
 
-    pub fn get_pubkey(&self) -> &[u8] {
-        self.pub_key.as_ref()
-    }
-
     pub fn as_kcontact(&self) -> Kcontact {
         Kcontact {
             uid: self.uid.as_bytes().to_owned(),
@@ -44,6 +40,11 @@ impl Contact {
         }
     }
 
+    // whether the contact's NodeID is derived from its public key
+    pub fn is_verified(&self) -> bool {
+        verify_node_id(self.uid, &self.pub_key)
+    }
+
     pub fn see(&mut self) {
         self.last_seen = LastSeen::Seen(Utc::now());
     }
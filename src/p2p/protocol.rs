@@ -1,20 +1,24 @@
 use std::sync::Arc;
 
+use primitive_types::H256;
+
 use prost::Message;
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status, Code};
-use crate::{p2p::util::{gen_cookie, grpc_block}};
+use crate::{ledger::{genesis::genesis, sync::{MAX_HEADERS, MAX_LOCATOR}}, p2p::util::{grpc_block, grpc_header, grpc_proof}};
 
 use super::{kad::KadNode, 
     key::NodeID, 
     node::{Contact}, 
+    replay::Stamp,
     signatures::Signer, 
+    transport::{connect, peer},
     kademlia::{
         kademlia_server::{Kademlia, KademliaServer}, 
         PingM, Kcontact, StoreReq, StoreRepl, FValueReq, FValueRepl,
         f_value_repl::{HasValue::{Auction,Node as HNode}, HasValue},
-        Kclosest, Header, FNodeReq, FNodeRepl, Auctions, BroadcastReq, Empty, Gblock, kademlia_client::KademliaClient}, util::{to_gossip, to_auction_data_vec, encode_fvalue, encode_store, format_address, to_data, to_block, build_brequest}};
+        Kclosest, Header, FNodeReq, FNodeRepl, Auctions, BroadcastReq, Empty, Gblock, CountersignReq, CountersignRepl, BalanceReq, BalanceRepl, HeadersReq, HeadersRepl, BlocksReq, ProofReq, ProofRepl}, util::{to_gossip, to_auction_data_vec, encode_fvalue, encode_store, try_to_data, try_to_block, build_brequest, grpc_transaction, encode_balance}};
 
 #[derive(Debug)]
 pub struct KademliaProtocol{
@@ -55,27 +59,31 @@ impl KademliaProtocol {
 #[tonic::async_trait]
 impl Kademlia for KademliaProtocol {
    async fn ping(&self, request: Request<PingM>) -> Result<Response<PingM>,Status>{
-        if let Some(sender_addr) = request.remote_addr() {
-            println!("Hello from the server side {:?}",sender_addr);
+        if let Some(sender) = peer(&request) {
+            println!("Hello from the server side {:?}",sender.address);
         }
         println!("validating {:?}", &request);
-        let remote_addr = request.remote_addr().unwrap();
+        let sender = peer(&request).unwrap();
         let req = request.into_inner();
         let header = req.header.unwrap();
         
-        if let Ok(req_hash) = Signer::validate_weak_req(self.node.get_validator(),&header,&remote_addr.to_string()) {
-            println!("validated ping{:?}", remote_addr);
+        if let Ok(req_hash) = Signer::validate_weak_req(&header,&sender) {
+            println!("validated ping{:?}", sender.address);
+            if let Err(e) = self.node.check_replay(sender.node_id, Stamp::of(&header)) {
+                return Err(Status::new(Code::Aborted, e));
+            }
 
             self.insert_update(header.my_id,&header.pub_key,header.address);
-            let timestamp = gen_cookie();
+            let stamp = Stamp::now();
             let reply = PingM {
                 header: Some( Header {
                     my_id: self.node.uid.as_bytes().to_owned(),
                     address : self.node.address.to_owned(),
                     pub_key: self.node.get_pubkey(),
                     nonce: self.node.get_nonce(),
-                    timestamp,
-                    signature: Signer::sign_weak_header_rep(timestamp,&header.pub_key, &self.node.address, &req_hash),
+                    timestamp: stamp.time,
+                    cookie: stamp.cookie,
+                    signature: Signer::sign_weak_header_rep(self.node.get_validator(),stamp,&self.node.address, &req_hash),
                 }),
             };
             println!("Sending reply: {:?}", reply);
@@ -85,28 +93,30 @@ impl Kademlia for KademliaProtocol {
     }
 
     async fn store(&self, request: Request<StoreReq>) -> Result<Response<StoreRepl>,Status>{
-        let remote_addr = request.remote_addr().unwrap();
+        let sender = peer(&request).unwrap();
         let req = request.into_inner();
         let header = req.header.unwrap();
         let key =NodeID::from_vec(req.target_id);
         let value = req.value.unwrap();
         let databuf = encode_store(&value,key);
-        if let Ok(req_hash) = Signer::validate_strong_req(self.node.get_validator(),&header,&remote_addr.to_string(),&databuf) {
-            println!("validated store from {:?}", remote_addr);
+        if let Ok(req_hash) = Signer::validate_strong_req(&header,&sender,&databuf) {
+            println!("validated store from {:?}", sender.address);
+            if let Err(e) = self.node.check_replay(sender.node_id, Stamp::of(&header)) {
+                return Err(Status::new(Code::Aborted, e));
+            }
             self.insert_update(header.my_id,&header.pub_key,header.address);
             let value = to_gossip(&value);
-            let timestamp = self.node.compare(header.timestamp);
-            //if timestamp == header.timestamp + 1 {
+            let stamp = Stamp::now();
             let _ = self.node.store_value(key, value);
-            //}
             let reply = StoreRepl {
                 header: Some( Header { 
                     my_id: self.node.uid.as_bytes().to_owned(),
                     address : self.node.address.to_owned(),
                     pub_key: self.node.get_pubkey(),
                     nonce: self.node.get_nonce(),
-                    timestamp,
-                    signature : Signer::sign_weak_header_rep(timestamp,&header.pub_key,&self.node.address, &req_hash) 
+                    timestamp: stamp.time,
+                    cookie: stamp.cookie,
+                    signature : Signer::sign_weak_header_rep(self.node.get_validator(),stamp,&self.node.address, &req_hash) 
                 }),
             };
 
@@ -117,12 +127,15 @@ impl Kademlia for KademliaProtocol {
     }
 
     async fn find_value(&self, request: Request<FValueReq>) -> Result<Response<FValueRepl>,Status>{
-        let remote_addr = request.remote_addr().unwrap();
+        let sender = peer(&request).unwrap();
         let req = request.into_inner();
         let key_bytes = req.target_id;
         let header = req.header.unwrap();
-        if let Ok(req_hash) = Signer::validate_strong_req(self.node.get_validator(),&header,&remote_addr.to_string(),&key_bytes) {
-            println!("validated find value from {:?}", remote_addr);
+        if let Ok(req_hash) = Signer::validate_strong_req(&header,&sender,&key_bytes) {
+            println!("validated find value from {:?}", sender.address);
+            if let Err(e) = self.node.check_replay(sender.node_id, Stamp::of(&header)) {
+                return Err(Status::new(Code::Aborted, e));
+            }
             self.insert_update(header.my_id,&header.pub_key,header.address);
             let lookup_key = NodeID::from_vec(key_bytes);
             let has_value : HasValue;
@@ -133,15 +146,16 @@ impl Kademlia for KademliaProtocol {
                                     }),
             };
             let databuf = encode_fvalue(&has_value, lookup_key);
-            let timestamp = self.node.compare(header.timestamp);
+            let stamp = Stamp::now();
             let reply = FValueRepl {
                 header: Some(Header { 
                     my_id: self.node.uid.as_bytes().to_owned(),
                     address : self.node.address.to_owned(),
                     pub_key: self.node.get_pubkey(),
                     nonce: self.node.get_nonce(),
-                    timestamp,
-                    signature : Signer::sign_strong_header_rep(timestamp,&header.pub_key,&self.node.address,databuf, &req_hash),
+                    timestamp: stamp.time,
+                    cookie: stamp.cookie,
+                    signature : Signer::sign_strong_header_rep(self.node.get_validator(),stamp,&self.node.address,databuf, &req_hash),
                 }),
                 has_value: Some(has_value),
             };
@@ -153,12 +167,15 @@ impl Kademlia for KademliaProtocol {
     }
 
     async fn find_node(&self, request: Request<FNodeReq>) -> Result<Response<FNodeRepl>,Status>{
-        let remote_addr = request.remote_addr().unwrap();
+        let sender = peer(&request).unwrap();
         let req = request.into_inner();
         let header = req.header.unwrap();
         let key_bytes = req.target_id;
-        if let Ok(req_hash) = Signer::validate_weak_req(self.node.get_validator(),&header,&remote_addr.to_string()) {
-            println!("validated find node {:?}", remote_addr);
+        if let Ok(req_hash) = Signer::validate_weak_req(&header,&sender) {
+            println!("validated find node {:?}", sender.address);
+            if let Err(e) = self.node.check_replay(sender.node_id, Stamp::of(&header)) {
+                return Err(Status::new(Code::Aborted, e));
+            }
 
             let lookup_key = NodeID::from_vec(key_bytes);
             let k = Kclosest {
@@ -167,15 +184,16 @@ impl Kademlia for KademliaProtocol {
             self.insert_update(header.my_id,&header.pub_key,header.address);
             let mut databuf = Vec::new();
             let _enc = k.encode(&mut databuf).unwrap();
-            let timestamp = self.node.compare(header.timestamp);
+            let stamp = Stamp::now();
             let reply = FNodeRepl {
                 header: Some( Header { 
                     my_id: self.node.uid.as_bytes().to_owned(),
                     address : self.node.address.to_owned(),
                     pub_key: self.node.get_pubkey(),
                     nonce: self.node.get_nonce(),
-                    timestamp,
-                    signature : Signer::sign_strong_header_rep(timestamp,&header.pub_key, &self.node.address,databuf, &req_hash),
+                    timestamp: stamp.time,
+                    cookie: stamp.cookie,
+                    signature : Signer::sign_strong_header_rep(self.node.get_validator(),stamp,&self.node.address,databuf, &req_hash),
                 }),
                 nodes: Some(k),
             };
@@ -186,26 +204,42 @@ impl Kademlia for KademliaProtocol {
     }
 
     async fn broadcast(&self, request: Request<BroadcastReq>) -> Result<Response<Empty>,Status> {
-        println!("broadcast {:?}", request.remote_addr());
+        println!("broadcast {:?}", peer(&request).map(|sender| sender.address));
         let req = request.into_inner();
         let timestamp = self.node.compare_broadcast(req.timestamp);
         if timestamp == req.timestamp + 1 {
             let data = &req.rdata.unwrap();
             match data {
                 super::kademlia::broadcast_req::Rdata::Block(b) => {
-                    self.node.store_block(to_block(b.clone()));
+                    match try_to_block(b.clone()) {
+                        Some(block) => self.node.store_block(block),
+                        None => return Err(Status::new(Code::InvalidArgument, "Malformed block")),
+                    }
                     if let Err(e) = self.node.validate_blocks() {
                         println!("{}", e);
                     }
                 },
                 super::kademlia::broadcast_req::Rdata::Transaction(t) => {
-                    self.node.store_transaction(to_data(t.clone()));
-                    let _ = self.node.mine_and_broadcast().await;
+                    let data = match try_to_data(t.clone()) {
+                        Some(data) => data,
+                        None => return Err(Status::new(Code::InvalidArgument, "Malformed transaction")),
+                    };
+                    if let Err(e) = data.verify_signatures() {
+                        return Err(Status::new(Code::InvalidArgument, e));
+                    }
+                    match self.node.store_transaction(data) {
+                        Ok(()) => {
+                            // mining in the background lets the transaction keep spreading meanwhile
+                            let node = self.node.clone();
+                            tokio::spawn(async move { node.mine_and_broadcast().await });
+                        },
+                        Err(e) => println!("Transaction rejected: {}", e),
+                    }
                 },
             }
-            let my_closest = self.lookup(self.node.uid);
+            let my_closest = self.node.lookup(self.node.uid);
             for close in my_closest {
-                let connection = KademliaClient::connect(format_address(close.address)).await;
+                let connection = connect(self.node.get_validator(), &close).await;
                 match connection {
                     Ok(mut chan) => {
                         let _ = chan.broadcast(build_brequest(&req.timestamp,&data)).await;
@@ -220,28 +254,157 @@ impl Kademlia for KademliaProtocol {
         
     }
 
-    type req_chainStream =  ReceiverStream<Result<Gblock, Status>>;
+    async fn countersign(&self, request: Request<CountersignReq>) -> Result<Response<CountersignRepl>,Status> {
+        let sender = peer(&request).unwrap();
+        let req = request.into_inner();
+        let header = req.header.unwrap();
+        let transaction = match req.transaction {
+            Some(transaction) => transaction,
+            None => return Err(Status::new(Code::InvalidArgument, "Missing transaction")),
+        };
+        let databuf = transaction.encode_to_vec();
+        if let Ok(req_hash) = Signer::validate_strong_req(&header,&sender,&databuf) {
+            println!("validated countersign from {:?}", sender.address);
+            if let Err(e) = self.node.check_replay(sender.node_id, Stamp::of(&header)) {
+                return Err(Status::new(Code::Aborted, e));
+            }
+            self.insert_update(header.my_id,&header.pub_key,header.address);
+            let data = match try_to_data(transaction) {
+                Some(data) => data,
+                None => return Err(Status::new(Code::InvalidArgument, "Malformed transaction")),
+            };
+            let signed = match self.node.countersign(data) {
+                Ok(signed) => signed,
+                Err(e) => return Err(Status::new(Code::PermissionDenied, e)),
+            };
+            let stamp = Stamp::now();
+            let reply = CountersignRepl {
+                header: Some(Header {
+                    my_id: self.node.uid.as_bytes().to_owned(),
+                    address : self.node.address.to_owned(),
+                    pub_key: self.node.get_pubkey(),
+                    nonce: self.node.get_nonce(),
+                    timestamp: stamp.time,
+                    cookie: stamp.cookie,
+                    signature : Signer::sign_weak_header_rep(self.node.get_validator(),stamp,&self.node.address, &req_hash),
+                }),
+                transaction: Some(grpc_transaction(signed)),
+            };
+            return Ok(Response::new(reply));
+        }
+        Err(Status::new(Code::InvalidArgument, "Invalid message"))
+    }
 
-    async fn req_chain(&self, request: Request<PingM>) -> Result<Response<Self::req_chainStream>, Status> {
-        let remote_addr = request.remote_addr().unwrap();
+    async fn balance(&self, request: Request<BalanceReq>) -> Result<Response<BalanceRepl>,Status> {
+        let sender = peer(&request).unwrap();
         let req = request.into_inner();
         let header = req.header.unwrap();
-        
-        if let Ok(_) = Signer::validate_weak_req(self.node.get_validator(),&header,&remote_addr.to_string()) {
-            println!("validated chain{:?}", remote_addr);
-            let _timestamp = self.node.compare(header.timestamp);
+        if req.account.len() != 32 {
+            return Err(Status::new(Code::InvalidArgument, "Malformed account"));
+        }
+        if let Ok(req_hash) = Signer::validate_strong_req(&header,&sender,&req.account) {
+            println!("validated balance from {:?}", sender.address);
+            if let Err(e) = self.node.check_replay(sender.node_id, Stamp::of(&header)) {
+                return Err(Status::new(Code::Aborted, e));
+            }
+            self.insert_update(header.my_id,&header.pub_key,header.address);
+            let account = NodeID::from_vec(req.account);
+            let (balance, height) = self.node.balance(account);
+            let stamp = Stamp::now();
+            let reply = BalanceRepl {
+                header: Some(Header {
+                    my_id: self.node.uid.as_bytes().to_owned(),
+                    address : self.node.address.to_owned(),
+                    pub_key: self.node.get_pubkey(),
+                    nonce: self.node.get_nonce(),
+                    timestamp: stamp.time,
+                    cookie: stamp.cookie,
+                    signature : Signer::sign_strong_header_rep(self.node.get_validator(),stamp,&self.node.address,encode_balance(account, balance, height), &req_hash),
+                }),
+                balance: balance.units(),
+                height,
+            };
+            return Ok(Response::new(reply));
+        }
+        Err(Status::new(Code::InvalidArgument, "Invalid message"))
+    }
+
+    async fn get_headers(&self, request: Request<HeadersReq>) -> Result<Response<HeadersRepl>, Status> {
+        let sender = peer(&request).unwrap();
+        let req = request.into_inner();
+        let header = req.header.unwrap();
+
+        if Signer::validate_weak_req(&header,&sender).is_ok() {
+            println!("validated headers from {:?}", sender.address);
+            if let Err(e) = self.node.check_replay(sender.node_id, Stamp::of(&header)) {
+                return Err(Status::new(Code::Aborted, e));
+            }
+            if req.genesis != genesis().hash.as_bytes() {
+                return Err(Status::new(Code::FailedPrecondition, "Different genesis"));
+            }
+            let locator: Vec<H256> = req.locator.iter().take(MAX_LOCATOR).filter(|h| h.len() == 32).map(|h| H256::from_slice(h)).collect();
+            let headers = self.node.headers_after(&locator);
+            return Ok(Response::new(HeadersRepl {
+                headers: headers.iter().map(grpc_header).collect(),
+                genesis: genesis().hash.as_bytes().to_owned(),
+            }));
+        }
+        Err(Status::new(Code::InvalidArgument, "Invalid message"))
+    }
+
+    type get_blocksStream =  ReceiverStream<Result<Gblock, Status>>;
+
+    async fn get_blocks(&self, request: Request<BlocksReq>) -> Result<Response<Self::get_blocksStream>, Status> {
+        let sender = peer(&request).unwrap();
+        let req = request.into_inner();
+        let header = req.header.unwrap();
+
+        if Signer::validate_weak_req(&header,&sender).is_ok() {
+            println!("validated blocks from {:?}", sender.address);
+            if let Err(e) = self.node.check_replay(sender.node_id, Stamp::of(&header)) {
+                return Err(Status::new(Code::Aborted, e));
+            }
+            if req.hashes.len() > MAX_HEADERS || req.hashes.iter().any(|h| h.len() != 32) {
+                return Err(Status::new(Code::InvalidArgument, "Malformed block hashes"));
+            }
+            let hashes: Vec<H256> = req.hashes.iter().map(|h| H256::from_slice(h)).collect();
+            let blocks = self.node.get_blocks(&hashes);
             let (tx, rx) = mpsc::channel(4);
-            let chain= self.node.get_chain();
             tokio::spawn(async move {
-                for block in chain.blocks.iter() {
-                        tx.send(Ok(grpc_block(block.clone()))).await.unwrap();
+                for block in blocks {
+                    // the peer hung up
+                    if tx.send(Ok(grpc_block(block))).await.is_err() {
+                        break;
+                    }
                 }
             });
 
             Ok(Response::new(ReceiverStream::new(rx)))
-        } 
+        }
         else {
             Err(Status::new(Code::InvalidArgument, "Invalid message"))
         }
     }
+
+    // the proof is checked against the requester's headers, so the reply isn't signed
+    async fn get_proof(&self, request: Request<ProofReq>) -> Result<Response<ProofRepl>, Status> {
+        let sender = peer(&request).unwrap();
+        let req = request.into_inner();
+        let header = req.header.unwrap();
+        if req.auction_id.len() != 32 {
+            return Err(Status::new(Code::InvalidArgument, "Malformed auction id"));
+        }
+
+        if Signer::validate_weak_req(&header,&sender).is_ok() {
+            println!("validated proof from {:?}", sender.address);
+            if let Err(e) = self.node.check_replay(sender.node_id, Stamp::of(&header)) {
+                return Err(Status::new(Code::Aborted, e));
+            }
+            let proof = self.node.prove_settlement(H256::from_slice(&req.auction_id));
+            return Ok(Response::new(ProofRepl {
+                proof: proof.map(grpc_proof),
+            }));
+        }
+        Err(Status::new(Code::InvalidArgument, "Invalid message"))
+    }
 }
@@ -0,0 +1,137 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use chrono::Utc;
+use parking_lot::Mutex;
+
+use super::{kademlia::Header, key::NodeID, util::gen_cookie};
+
+// how far a request's time can be from our clock, either way, in milliseconds
+pub const MAX_SKEW: u64 = 30_000;
+// requests remembered per peer, the older ones are refused once forgotten
+const WINDOW: usize = 1024;
+const MAX_PEERS: usize = 4096;
+
+/* When a request was signed, in unix milliseconds, and a random cookie telling apart the
+   requests signed in the same millisecond. Both are covered by the header signature. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Stamp {
+    pub time: u64,
+    pub cookie: u64,
+}
+
+impl Stamp {
+    pub fn now() -> Stamp {
+        Stamp { time: now_millis(), cookie: gen_cookie() }
+    }
+
+    pub fn of(header: &Header) -> Stamp {
+        Stamp { time: header.timestamp, cookie: header.cookie }
+    }
+}
+
+pub fn now_millis() -> u64 {
+    Utc::now().timestamp_millis() as u64
+}
+
+// the stamps a peer used within the last MAX_SKEW, by arrival
+#[derive(Debug, Default)]
+struct Window {
+    seen: HashSet<Stamp>,
+    order: VecDeque<Stamp>,
+    // stamps up to this time were forgotten to keep the window bounded
+    floor: u64,
+    latest: u64,
+}
+
+impl Window {
+    fn insert(&mut self, stamp: Stamp, now: u64) -> Result<(), &'static str> {
+        if stamp.time <= self.floor {
+            return Err("stale request");
+        }
+        if self.seen.contains(&stamp) {
+            return Err("replayed request");
+        }
+        // stamps out of the skew are refused anyway, they don't need remembering
+        while let Some(oldest) = self.order.front().filter(|s| s.time.saturating_add(MAX_SKEW) < now).copied() {
+            self.order.pop_front();
+            self.seen.remove(&oldest);
+        }
+        if self.order.len() == WINDOW {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+                self.floor = self.floor.max(oldest.time);
+            }
+        }
+        self.order.push_back(stamp);
+        self.seen.insert(stamp);
+        self.latest = self.latest.max(stamp.time);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Windows {
+    peers: HashMap<NodeID, Window>,
+    // latest stamp time of the recent peers whose window was evicted, their floor if they come back
+    evicted: HashMap<NodeID, u64>,
+}
+
+/* Refuses requests whose stamp was already used by the same peer, or that were signed too far
+   from our clock. Peers are told apart by the NodeID their connection was authenticated with,
+   so a request can't be replayed under another identity. */
+#[derive(Debug, Default)]
+pub struct ReplayGuard {
+    windows: Mutex<Windows>,
+}
+
+impl ReplayGuard {
+    pub fn new() -> ReplayGuard {
+        ReplayGuard::default()
+    }
+
+    pub fn check(&self, peer: NodeID, stamp: Stamp) -> Result<(), &'static str> {
+        self.check_at(peer, stamp, now_millis())
+    }
+
+    fn check_at(&self, peer: NodeID, stamp: Stamp, now: u64) -> Result<(), &'static str> {
+        if stamp.time.saturating_add(MAX_SKEW) < now || stamp.time > now.saturating_add(MAX_SKEW) {
+            return Err("stale request");
+        }
+        let mut windows = self.windows.lock();
+        if !windows.peers.contains_key(&peer) {
+            if windows.peers.len() >= MAX_PEERS {
+                forget_peers(&mut windows, now);
+            }
+            let floor = windows.evicted.remove(&peer).unwrap_or_default();
+            windows.peers.insert(peer, Window { floor, ..Window::default() });
+        }
+        windows.peers.entry(peer).or_default().insert(stamp, now)
+    }
+}
+
+/* Drops the peers whose stamps are all out of the skew. If every peer is recent, the window of
+   the one heard from least recently goes, and that peer alone gets its stamps up to its latest
+   refused if it comes back. Other peers aren't affected by how many peers there are. */
+fn forget_peers(windows: &mut Windows, now: u64) {
+    windows.peers.retain(|_, window| window.latest.saturating_add(MAX_SKEW) >= now);
+    if windows.peers.len() < MAX_PEERS {
+        return;
+    }
+    let oldest = windows.peers.iter().min_by_key(|(_, window)| window.latest).map(|(id, window)| (*id, window.latest));
+    if let Some((id, latest)) = oldest {
+        windows.peers.remove(&id);
+        evict(windows, id, latest, now);
+    }
+}
+
+// floors past the skew refuse nothing the skew doesn't, the oldest one goes if there are still too many
+fn evict(windows: &mut Windows, peer: NodeID, latest: u64, now: u64) {
+    windows.evicted.retain(|_, floor| floor.saturating_add(MAX_SKEW) >= now);
+    if windows.evicted.len() >= MAX_PEERS {
+        let oldest = windows.evicted.iter().min_by_key(|(_, floor)| **floor).map(|(id, _)| *id);
+        if let Some(id) = oldest {
+            windows.evicted.remove(&id);
+        }
+    }
+    windows.evicted.insert(peer, latest);
+}
@@ -0,0 +1,52 @@
+use openssl::{
+    error::ErrorStack,
+    hash::MessageDigest,
+    pkey::{Id, PKeyRef, Private, Public},
+    rsa::Padding,
+    sign::{RsaPssSaltlen, Signer, Verifier},
+};
+
+/* Signature schemes a node key can use. The scheme follows from the key type, which PEM public
+   keys already carry, so a signature is checked against the sender's key without any extra tag. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    // RSA-PSS over SHA-256, with a salt as long as the digest
+    RsaPss,
+    Ed25519,
+}
+
+impl Scheme {
+    // None for key types we don't sign with
+    pub fn of<T>(key: &PKeyRef<T>) -> Option<Scheme> {
+        match key.id() {
+            Id::RSA => Some(Scheme::RsaPss),
+            Id::ED25519 => Some(Scheme::Ed25519),
+            _ => None,
+        }
+    }
+
+    pub fn sign(self, key: &PKeyRef<Private>, data: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+        match self {
+            Scheme::RsaPss => {
+                let mut signer = Signer::new(MessageDigest::sha256(), key)?;
+                signer.set_rsa_padding(Padding::PKCS1_PSS)?;
+                signer.set_rsa_pss_saltlen(RsaPssSaltlen::DIGEST_LENGTH)?;
+                signer.sign_oneshot_to_vec(data)
+            },
+            // Ed25519 hashes the message itself
+            Scheme::Ed25519 => Signer::new_without_digest(key)?.sign_oneshot_to_vec(data),
+        }
+    }
+
+    pub fn verify(self, key: &PKeyRef<Public>, data: &[u8], signature: &[u8]) -> bool {
+        let verified = match self {
+            Scheme::RsaPss => Verifier::new(MessageDigest::sha256(), key).and_then(|mut verifier| {
+                verifier.set_rsa_padding(Padding::PKCS1_PSS)?;
+                verifier.set_rsa_pss_saltlen(RsaPssSaltlen::DIGEST_LENGTH)?;
+                verifier.verify_oneshot(signature, data)
+            }),
+            Scheme::Ed25519 => Verifier::new_without_digest(key).and_then(|mut verifier| verifier.verify_oneshot(signature, data)),
+        };
+        verified.unwrap_or(false)
+    }
+}
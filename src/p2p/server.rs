@@ -2,7 +2,7 @@ use std::{net::SocketAddr, sync::Arc};
 
 use tonic::transport::Server;
 
-use super::{kad::KadNode, protocol};
+use super::{kad::KadNode, protocol, transport};
 
 pub async fn server(addr: SocketAddr, node: Arc<KadNode>) {
     /*
@@ -17,11 +17,12 @@ pub async fn server(addr: SocketAddr, node: Arc<KadNode>) {
         let k = kad::KadNode::new(i.to_string(),1616);
         protocol.node.insert(k.as_contact());
     } */
+    let incoming = transport::incoming(addr, protocol.node.get_validator()).await.unwrap();
     let svc = protocol.create_server();
 
     Server::builder()
         .add_service(svc)
-        .serve(addr)
+        .serve_with_incoming(incoming)
         .await
         .unwrap();
 }
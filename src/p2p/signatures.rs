@@ -2,135 +2,117 @@ use openssl::sha::Sha256;
 
 use super::{
     kademlia::Header,
-    key::{encrypt_message, verify_puzzle, NodeID, NodeValidator},
+    key::{verify_node_id, verify_puzzle, verify_signature, NodeID, NodeValidator},
+    node::Contact,
+    replay::Stamp,
+    transport::PeerInfo,
 };
 
+/* Request and reply headers are signed by their sender with its own key, over a hash of the
+   stamp, an address and the message data. A reply's hash also covers the hash of the request
+   it answers. The receiver checks the signature against the `pub_key` in the header, which has to
+   hash to the sender's NodeID and solve the static puzzle. Requests and replies have to come from
+   the node the connection was authenticated with. Signing returns the hash too, so a requester
+   can match the reply. */
 pub struct Signer {}
 
 impl Signer {
-    
-    pub fn sign_strong_header_req(timestamp: u64, pub_key: &[u8], address: &str, data: &[u8]) -> (Vec<u8>,Vec<u8>) {
+
+    pub fn sign_strong_header_req(validator: &NodeValidator, stamp: Stamp, address: &str, data: &[u8]) -> (Vec<u8>,Vec<u8>) {
         let ipaddr: Vec<&str> = address.split(':').collect();
-        let mut hasher = Sha256::new();
-        hasher.update(&timestamp.to_be_bytes());
+        let mut hasher = Signer::hasher(stamp);
         hasher.update(ipaddr[0].as_bytes());
-        hasher.update(&data);
-        let signature = hasher.finish().to_vec();
-        (signature.clone(),encrypt_message(pub_key, &signature))
+        hasher.update(data);
+        let hash = hasher.finish().to_vec();
+        (hash.clone(),validator.sign(&hash))
     }
 
-    pub fn sign_strong_header_rep(timestamp: u64, pub_key: &[u8], address: &str, data: Vec<u8>, rep_signature: &[u8]) -> Vec<u8> {
-        let mut hasher = Sha256::new();
-        hasher.update(&timestamp.to_be_bytes());
-        hasher.update(&address.as_bytes());
+    pub fn sign_strong_header_rep(validator: &NodeValidator, stamp: Stamp, address: &str, data: Vec<u8>, req_hash: &[u8]) -> Vec<u8> {
+        let mut hasher = Signer::hasher(stamp);
+        hasher.update(address.as_bytes());
         hasher.update(&data);
-        hasher.update(rep_signature);
-        encrypt_message(pub_key, &hasher.finish())
+        hasher.update(req_hash);
+        validator.sign(&hasher.finish())
     }
 
-    pub fn sign_weak_header_req(timestamp: u64, pub_key: &[u8], address: &str) -> (Vec<u8>, Vec<u8>) {
+    pub fn sign_weak_header_req(validator: &NodeValidator, stamp: Stamp, address: &str) -> (Vec<u8>, Vec<u8>) {
         let ipaddr: Vec<&str> = address.split(':').collect();
 
-        let mut hasher = Sha256::new();
-        hasher.update(&timestamp.to_be_bytes());
+        let mut hasher = Signer::hasher(stamp);
         hasher.update(ipaddr[0].as_bytes());
-        let signature = hasher.finish().to_vec();
-        (signature.clone(),encrypt_message(pub_key, &signature))
+        let hash = hasher.finish().to_vec();
+        (hash.clone(),validator.sign(&hash))
     }
 
-    pub fn sign_weak_header_rep(timestamp: u64,pub_key: &[u8],address: &str,rep_signature: &[u8]) -> Vec<u8> {
-        let mut hasher = Sha256::new();
-        hasher.update(&timestamp.to_be_bytes());
+    pub fn sign_weak_header_rep(validator: &NodeValidator, stamp: Stamp, address: &str, req_hash: &[u8]) -> Vec<u8> {
+        let mut hasher = Signer::hasher(stamp);
         hasher.update(address.as_bytes());
-        hasher.update(rep_signature);
-
-        encrypt_message(pub_key, &hasher.finish())
+        hasher.update(req_hash);
+        validator.sign(&hasher.finish())
     }
 
-    pub fn validate_weak_req(validator: &NodeValidator,header: &Header, address: &str) -> Result<Vec<u8>, &'static str> {
-        let node_id = NodeID::from_vec(header.my_id.clone());
-        let nonce = header.nonce;
-
-        if verify_puzzle(node_id, nonce) {
-            let ipaddr: Vec<&str> = address.split(':').collect();
-            let mut signature = validator.decrypt(&header.signature);
-            signature.truncate(32);
-            let timestamp = header.timestamp;
-            let mut hasher = Sha256::new();
-            hasher.update(&timestamp.to_be_bytes());
-            hasher.update(ipaddr[0].as_bytes());
-            let sign = hasher.finish().to_vec();
-            if signature == sign {
-                return Ok(sign);
-            }
+    pub fn validate_weak_req(header: &Header, peer: &PeerInfo) -> Result<Vec<u8>, &'static str> {
+        if header.my_id != peer.node_id.as_bytes() {
+            return Err("request from a different node");
         }
-
-        Err("invalid message")
+        let mut hasher = Signer::hasher(Stamp::of(header));
+        hasher.update(peer.address.ip().to_string().as_bytes());
+        let hash = hasher.finish().to_vec();
+        Signer::verify_header(header, &hash)?;
+        Ok(hash)
     }
 
-    pub fn validate_weak_rep(validator: &NodeValidator, header: &Header, address: &str,req_signature: &[u8]) -> Result<(), &'static str> {
-        let node_id = NodeID::from_vec(header.my_id.clone());
-        let nonce = header.nonce;
-
-        if verify_puzzle(node_id, nonce) {
-            let mut signature = validator.decrypt(&header.signature);
-            signature.truncate(32);
-            let timestamp = header.timestamp;
-            let mut hasher = Sha256::new();
-            hasher.update(&timestamp.to_be_bytes());
-            hasher.update(address.as_bytes());
-            hasher.update(req_signature);
-            let sign = hasher.finish().to_vec();
-            if sign == signature {
-                return Ok(());
-            }
+    pub fn validate_weak_rep(header: &Header, contact: &Contact, req_hash: &[u8]) -> Result<(), &'static str> {
+        if header.my_id != contact.uid.as_bytes() {
+            return Err("reply from a different node");
         }
-
-        Err("invalid message")
+        let mut hasher = Signer::hasher(Stamp::of(header));
+        hasher.update(contact.address.as_bytes());
+        hasher.update(req_hash);
+        Signer::verify_header(header, &hasher.finish())
     }
 
-    pub fn validate_strong_rep(validator: &NodeValidator,header: &Header,address: &str,data: &[u8],req_signature: &[u8]) -> Result<(), &'static str> {
-        let node_id = NodeID::from_vec(header.my_id.clone());
-        let nonce = header.nonce;
-
-        if verify_puzzle(node_id, nonce) {
-            let mut signature = validator.decrypt(&header.signature);
-            signature.truncate(32);
-            let timestamp = header.timestamp;
-            let mut hasher = Sha256::new();
-            hasher.update(&timestamp.to_be_bytes());
-            hasher.update(address.as_bytes());
-            hasher.update(data);
-            hasher.update(req_signature);
-            let sign = hasher.finish().to_vec();
-            if sign == signature {
-                return Ok(());
-            }
+    pub fn validate_strong_rep(header: &Header, contact: &Contact, data: &[u8], req_hash: &[u8]) -> Result<(), &'static str> {
+        if header.my_id != contact.uid.as_bytes() {
+            return Err("reply from a different node");
         }
+        let mut hasher = Signer::hasher(Stamp::of(header));
+        hasher.update(contact.address.as_bytes());
+        hasher.update(data);
+        hasher.update(req_hash);
+        Signer::verify_header(header, &hasher.finish())
+    }
 
-        Err("invalid message")
+    pub fn validate_strong_req(header: &Header, peer: &PeerInfo, data: &[u8]) -> Result<Vec<u8>, &'static str> {
+        if header.my_id != peer.node_id.as_bytes() {
+            return Err("request from a different node");
+        }
+        let mut hasher = Signer::hasher(Stamp::of(header));
+        hasher.update(peer.address.ip().to_string().as_bytes());
+        hasher.update(data);
+        let hash = hasher.finish().to_vec();
+        Signer::verify_header(header, &hash)?;
+        Ok(hash)
     }
 
-    pub fn validate_strong_req(validator: &NodeValidator, header: &Header, address: &str, data: &[u8]) -> Result<Vec<u8>, &'static str> {
-        let node_id = NodeID::from_vec(header.my_id.clone());
-        let nonce = header.nonce;
-      
-        if verify_puzzle(node_id, nonce) {
-            let ipaddr: Vec<&str> = address.split(':').collect();
-            let mut signature = validator.decrypt(&header.signature);
-            signature.truncate(32);
-            let timestamp = header.timestamp;
-            let mut hasher = Sha256::new();
-            hasher.update(&timestamp.to_be_bytes());
-            hasher.update(ipaddr[0].as_bytes());
-            hasher.update(data);
+    // every hash starts with the stamp, so a signature can't be reused under another one
+    fn hasher(stamp: Stamp) -> Sha256 {
+        let mut hasher = Sha256::new();
+        hasher.update(&stamp.time.to_be_bytes());
+        hasher.update(&stamp.cookie.to_be_bytes());
+        hasher
+    }
 
-            let sign = hasher.finish().to_vec();
-            if sign == signature {
-                return Ok(sign);
-            }
+    // the sender solved its NodeID's puzzles, owns the key the header carries and signed `hash` with it
+    fn verify_header(header: &Header, hash: &[u8]) -> Result<(), &'static str> {
+        if header.my_id.len() != 32 || !verify_puzzle(NodeID::from_vec(header.my_id.clone()), header.nonce) {
+            Err("invalid message")
+        } else if !verify_node_id(NodeID::from_vec(header.my_id.clone()), &header.pub_key) {
+            Err("sender's id isn't derived from its public key")
+        } else if !verify_signature(&header.pub_key, hash, &header.signature) {
+            Err("invalid signature")
+        } else {
+            Ok(())
         }
-
-        Err("invalid message")
     }
 }
@@ -0,0 +1,202 @@
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::OnceLock,
+    task::{Context, Poll},
+};
+
+use openssl::{
+    asn1::Asn1Time,
+    bn::BigNum,
+    error::ErrorStack,
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{Id, PKeyRef, Private},
+    ssl::{Ssl, SslAcceptor, SslConnector, SslContext, SslMethod, SslRef, SslVerifyMode, SslVersion},
+    x509::{X509NameBuilder, X509},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tokio_openssl::SslStream;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{
+    transport::{server::Connected, Channel, Endpoint, Uri},
+    Request,
+};
+use tower::service_fn;
+
+use super::{
+    kademlia::kademlia_client::KademliaClient,
+    key::{node_id, verify_static_puzzle, NodeID, NodeValidator},
+    node::Contact,
+    util::format_address,
+};
+
+// handshaken connections waiting for the server to pick them up
+const PENDING_CONNECTIONS: usize = 64;
+const CERTIFICATE_DAYS: u32 = 3650;
+
+/* Peers talk TLS 1.3 with mutual authentication. Certificates are self-signed with the node key
+   and aren't checked against any CA: the key a peer proves it holds during the handshake has to
+   hash to the NodeID we expected to reach, or to a NodeID solving the static puzzle when the peer
+   is the one connecting. Requests carry the authenticated NodeID in their extensions. */
+#[derive(Debug, Clone, Copy)]
+pub struct PeerInfo {
+    pub address: SocketAddr,
+    pub node_id: NodeID,
+}
+
+// the peer a request came from, set on every connection the server accepts
+pub fn peer<T>(request: &Request<T>) -> Option<PeerInfo> {
+    request.extensions().get::<PeerInfo>().copied()
+}
+
+// an accepted connection and the peer it was authenticated as
+pub struct PeerStream {
+    stream: SslStream<TcpStream>,
+    peer: PeerInfo,
+}
+
+impl Connected for PeerStream {
+    type ConnectInfo = PeerInfo;
+
+    fn connect_info(&self) -> PeerInfo {
+        self.peer
+    }
+}
+
+impl AsyncRead for PeerStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for PeerStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/* Listens on `addr` and yields the connections whose handshake succeeded. Handshakes run in
+   their own tasks so a slow peer doesn't hold back the others. */
+pub async fn incoming(addr: SocketAddr, validator: &NodeValidator) -> io::Result<ReceiverStream<io::Result<PeerStream>>> {
+    let listener = TcpListener::bind(addr).await?;
+    let acceptor = acceptor(validator)?;
+    let (sender, receiver) = mpsc::channel(PENDING_CONNECTIONS);
+    tokio::spawn(async move {
+        loop {
+            let (tcp, address) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    println!("Could not accept a connection: {}", e);
+                    continue;
+                },
+            };
+            let (acceptor, sender) = (acceptor.clone(), sender.clone());
+            tokio::spawn(async move {
+                match accept(&acceptor, tcp, address).await {
+                    Ok(stream) => {
+                        let _ = sender.send(Ok(stream)).await;
+                    },
+                    Err(e) => println!("Refused connection from {}: {}", address, e),
+                }
+            });
+        }
+    });
+    Ok(ReceiverStream::new(receiver))
+}
+
+async fn accept(acceptor: &SslAcceptor, tcp: TcpStream, address: SocketAddr) -> io::Result<PeerStream> {
+    let mut stream = SslStream::new(Ssl::new(acceptor.context())?, tcp)?;
+    Pin::new(&mut stream).accept().await.map_err(io::Error::other)?;
+    let node_id = peer_id(stream.ssl()).ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "invalid peer id"))?;
+    Ok(PeerStream { stream, peer: PeerInfo { address, node_id } })
+}
+
+// a client for the contact, the connection fails unless the peer holds the key of the contact's NodeID
+pub async fn connect(validator: &NodeValidator, contact: &Contact) -> Result<KademliaClient<Channel>, tonic::transport::Error> {
+    let context = client_context(validator).clone();
+    let (address, expected) = (contact.address.clone(), contact.uid);
+    let channel = Endpoint::from_shared(format_address(contact.address.clone()))?
+        .connect_with_connector(service_fn(move |_: Uri| open(context.clone(), address.clone(), expected)))
+        .await?;
+    Ok(KademliaClient::new(channel))
+}
+
+async fn open(context: SslContext, address: String, expected: NodeID) -> io::Result<SslStream<TcpStream>> {
+    let tcp = TcpStream::connect(address).await?;
+    let mut stream = SslStream::new(Ssl::new(&context)?, tcp)?;
+    Pin::new(&mut stream).connect().await.map_err(io::Error::other)?;
+    if peer_id(stream.ssl()) != Some(expected) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "peer isn't the node we expected"));
+    }
+    Ok(stream)
+}
+
+// the NodeID the key in the peer's certificate hashes to, if it solves the static puzzle
+fn peer_id(ssl: &SslRef) -> Option<NodeID> {
+    let pem = ssl.peer_certificate()?.public_key().ok()?.public_key_to_pem().ok()?;
+    let id = node_id(&pem);
+    verify_static_puzzle(id).then_some(id)
+}
+
+fn acceptor(validator: &NodeValidator) -> Result<SslAcceptor, ErrorStack> {
+    let mut builder = SslAcceptor::mozilla_modern_v5(SslMethod::tls())?;
+    let certificate = certificate(validator.get_privkey(), validator.get_nodeid())?;
+    builder.set_private_key(validator.get_privkey())?;
+    builder.set_certificate(&certificate)?;
+    builder.check_private_key()?;
+    // any certificate will do, the peer is identified by its key once the handshake is done
+    builder.set_verify_callback(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT, |_, _| true);
+    Ok(builder.build())
+}
+
+// built once, a process runs a single node
+fn client_context(validator: &NodeValidator) -> &'static SslContext {
+    static CONTEXT: OnceLock<SslContext> = OnceLock::new();
+    CONTEXT.get_or_init(|| {
+        let build = || -> Result<SslContext, ErrorStack> {
+            let mut builder = SslConnector::builder(SslMethod::tls())?;
+            builder.set_min_proto_version(Some(SslVersion::TLS1_3))?;
+            let certificate = certificate(validator.get_privkey(), validator.get_nodeid())?;
+            builder.set_private_key(validator.get_privkey())?;
+            builder.set_certificate(&certificate)?;
+            builder.check_private_key()?;
+            builder.set_verify_callback(SslVerifyMode::PEER, |_, _| true);
+            Ok(builder.build().into_context())
+        };
+        build().expect("Could not set up the TLS client")
+    })
+}
+
+// self-signed, its only purpose is to carry the node key through the handshake
+fn certificate(key: &PKeyRef<Private>, node_id: NodeID) -> Result<X509, ErrorStack> {
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, &hex::encode(node_id.as_bytes()))?;
+    let name = name.build();
+
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    builder.set_serial_number(BigNum::from_u32(1)?.to_asn1_integer()?.as_ref())?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(key)?;
+    builder.set_not_before(Asn1Time::days_from_now(0)?.as_ref())?;
+    builder.set_not_after(Asn1Time::days_from_now(CERTIFICATE_DAYS)?.as_ref())?;
+    // Ed25519 hashes the certificate itself
+    let digest = if key.id() == Id::ED25519 { MessageDigest::null() } else { MessageDigest::sha256() };
+    builder.sign(key, digest)?;
+    Ok(builder.build())
+}
@@ -1,12 +1,12 @@
-use primitive_types::H256;
+use primitive_types::{H256, U256};
 use prost::Message;
 use rand::Rng;
 use tonic::Request;
 
-use crate::{auctions::auction::{AuctionGossip, AuctionState}, ledger::block::{Block, Data}};
+use crate::{auctions::auction::{AuctionGossip, AuctionState}, ledger::{amount::Amount, block::{Block, BlockHeader, Data, InclusionProof, TxSignature}, difficulty::target_bytes, merkle::MerkleProof, snapshot::Snapshot}};
 
 use super::{
-    kademlia::{AuctionData, f_value_repl::HasValue, Gblock, Transaction, BroadcastReq, broadcast_req::Rdata},
+    kademlia::{AuctionData, f_value_repl::HasValue, Gblock, Gheader, Gbalance, Gproof, Gsettlement, Gsnapshot, Transaction, BroadcastReq, broadcast_req::Rdata},
     key:: NodeID,
 };
 
@@ -40,7 +40,7 @@ pub fn to_gossip(auction: &AuctionData) -> AuctionGossip {
         id(&auction.auction_id), 
         auction.title.clone(),
         NodeID::from_vec(auction.buyer.clone()),
-        auction.price,
+        Amount::from_units(auction.price),
         state(auction.status),
         NodeID::from_vec(auction.seller.clone())
     )
@@ -56,7 +56,7 @@ pub fn to_auction_data(gossip: AuctionGossip) -> AuctionData {
         title: gossip.get_title(),
         seller: gossip.get_seller().as_bytes().to_owned(),
         buyer: gossip.get_buyer().as_bytes().to_owned(),
-        price: gossip.get_price(),
+        price: gossip.get_price().units(),
         status: gossip.get_bool_state(),
     }
 }
@@ -68,7 +68,7 @@ pub fn to_auction_data_vec(gossips: Vec<AuctionGossip>) -> Vec<AuctionData> {
             title: gossip.get_title(),
             seller: gossip.get_seller().as_bytes().to_owned(),
             buyer: gossip.get_buyer().as_bytes().to_owned(),
-            price: gossip.get_price(),
+            price: gossip.get_price().units(),
             status: gossip.get_bool_state(),
         }
     };
@@ -95,6 +95,14 @@ pub fn encode_fvalue(value: &HasValue, key: NodeID) -> Vec<u8> {
     databuf
 }
 
+// what a balance reply signs: the account, its balance and the height it was computed at
+pub fn encode_balance(account: NodeID, balance: Amount, height: u64) -> Vec<u8> {
+    let mut databuf = account.as_bytes().to_vec();
+    databuf.extend_from_slice(&balance.to_be_bytes());
+    databuf.extend_from_slice(&height.to_be_bytes());
+    databuf
+}
+
 pub fn gen_cookie() -> u64 {
     let mut rng = rand::thread_rng();
     let cookie: u64= rng.gen();
@@ -108,27 +116,75 @@ pub fn grpc_block(block: Block) -> Gblock {
         prev_hash: block.prev_hash.as_bytes().to_owned(), 
         current_hash: block.hash.as_bytes().to_owned(), 
         timestamp: block.timestamp, 
-        data: Some(grpc_transaction(block.data)), 
+        target: target_bytes(block.target),
+        merkle_root: block.merkle_root.as_bytes().to_owned(),
+        seal: block.seal,
+        transactions: block.transactions.into_iter().map(grpc_transaction).collect(),
+    }
+}
+
+pub fn grpc_header(block: &Block) -> Gheader {
+    Gheader {
+        id: block.id,
+        nonce: block.nonce,
+        prev_hash: block.prev_hash.as_bytes().to_owned(),
+        current_hash: block.hash.as_bytes().to_owned(),
+        timestamp: block.timestamp,
+        merkle_root: block.merkle_root.as_bytes().to_owned(),
+        target: target_bytes(block.target),
+        seal: block.seal.clone(),
     }
 }
 
+// a header-only block, None if a hash is malformed
+pub fn try_to_header(header: Gheader) -> Option<Block> {
+    let well_formed = [&header.prev_hash, &header.current_hash, &header.merkle_root, &header.target]
+        .iter()
+        .all(|field| field.len() == 32);
+    if !well_formed {
+        return None;
+    }
+
+    let block_header = BlockHeader {
+        id: header.id,
+        prev_hash: H256::from_slice(&header.prev_hash),
+        merkle_root: H256::from_slice(&header.merkle_root),
+        timestamp: header.timestamp,
+        target: U256::from_big_endian(&header.target),
+        nonce: header.nonce,
+    };
+    Some(Block::new(block_header, H256::from_slice(&header.current_hash), header.seal, Vec::new()))
+}
+
 pub fn grpc_transaction(data : Data) -> Transaction {
     Transaction {
         seller: data.get_seller().as_bytes().to_owned(),
         buyer: data.get_buyer().as_bytes().to_owned(),
-        amout: data.get_amount(),
+        amount: data.get_amount().units(),
+        fee: data.get_fee().units(),
         auction_id:data.get_auction_id().as_bytes().to_owned(),
+        seller_key: data.get_seller_signature().pub_key.clone(),
+        seller_signature: data.get_seller_signature().signature.clone(),
+        buyer_key: data.get_buyer_signature().pub_key.clone(),
+        buyer_signature: data.get_buyer_signature().signature.clone(),
     }
 }
 
 pub fn to_block(block: Gblock) -> Block {
+    let header = BlockHeader {
+        id: block.id,
+        prev_hash: H256::from_slice(block.prev_hash.as_slice()),
+        merkle_root: H256::from_slice(block.merkle_root.as_slice()),
+        timestamp: block.timestamp,
+        target: U256::from_big_endian(block.target.as_slice()),
+        nonce: block.nonce,
+    };
+
     Block::new(
-        block.id,
-        block.nonce,
-        H256::from_slice(block.prev_hash.as_slice()),
+        header,
         H256::from_slice(block.current_hash.as_slice()),
-        block.timestamp,
-        to_data(block.data.unwrap())
+        block.seal,
+        block.transactions.into_iter().map(to_data).collect()
     )
 }
 
@@ -136,14 +192,116 @@ pub fn to_data(data: Transaction) -> Data {
     Data::new(
         NodeID::from_vec(data.buyer), 
         NodeID::from_vec(data.seller),
-        data.amout,
+        Amount::from_units(data.amount),
         H256::from_slice(data.auction_id.as_slice())
+    ).with_fee(
+        Amount::from_units(data.fee)
+    ).with_signatures(
+        TxSignature { pub_key: data.seller_key, signature: data.seller_signature },
+        TxSignature { pub_key: data.buyer_key, signature: data.buyer_signature },
     )
 }
 
+pub fn try_to_data(data: Transaction) -> Option<Data> {
+    if [&data.seller, &data.buyer, &data.auction_id].iter().all(|field| field.len() == 32) {
+        Some(to_data(data))
+    } else {
+        None
+    }
+}
+
+pub fn grpc_proof(proof: InclusionProof) -> Gproof {
+    Gproof {
+        height: proof.height,
+        block_hash: proof.block_hash.as_bytes().to_owned(),
+        transaction: Some(grpc_transaction(proof.transaction)),
+        index: proof.proof.index,
+        leaves: proof.proof.leaves,
+        siblings: proof.proof.siblings.iter().map(|s| s.as_bytes().to_owned()).collect(),
+    }
+}
+
+pub fn try_to_proof(proof: Gproof) -> Option<InclusionProof> {
+    if proof.block_hash.len() != 32 || proof.siblings.iter().any(|s| s.len() != 32) {
+        return None;
+    }
+    Some(InclusionProof {
+        height: proof.height,
+        block_hash: H256::from_slice(&proof.block_hash),
+        transaction: try_to_data(proof.transaction?)?,
+        proof: MerkleProof {
+            index: proof.index,
+            leaves: proof.leaves,
+            siblings: proof.siblings.iter().map(|s| H256::from_slice(s)).collect(),
+        },
+    })
+}
+
+pub fn encode_block(block: &Block) -> Vec<u8> {
+    grpc_block(block.clone()).encode_to_vec()
+}
+
+pub fn decode_block(bytes: &[u8]) -> Option<Block> {
+    try_to_block(Gblock::decode(bytes).ok()?)
+}
+
+// same as to_block, but returns None instead of panicking on malformed hashes
+pub fn try_to_block(block: Gblock) -> Option<Block> {
+    let well_formed = [&block.prev_hash, &block.current_hash, &block.merkle_root, &block.target]
+        .into_iter()
+        .chain(block.transactions.iter().flat_map(|t| [&t.seller, &t.buyer, &t.auction_id]))
+        .all(|field| field.len() == 32);
+
+    if well_formed {
+        Some(to_block(block))
+    } else {
+        None
+    }
+}
+
 pub fn build_brequest(timestamp: &u64, data: &Rdata) -> Request<BroadcastReq> {
     Request::new(BroadcastReq {
         timestamp: timestamp.clone(),
         rdata: Some(data.clone()),
     })   
-}
\ No newline at end of file
+}
+
+pub fn encode_snapshot(snapshot: &Snapshot) -> Vec<u8> {
+    Gsnapshot {
+        height: snapshot.height,
+        hash: snapshot.hash.as_bytes().to_owned(),
+        balances: snapshot.balances.iter().map(|(account, balance)| Gbalance {
+            account: account.as_bytes().to_owned(),
+            balance: balance.units(),
+        }).collect(),
+        settlements: snapshot.settlements.iter().map(|(height, data)| Gsettlement {
+            height: *height,
+            transaction: Some(grpc_transaction(data.clone())),
+        }).collect(),
+        headers: snapshot.headers.iter().map(grpc_header).collect(),
+    }.encode_to_vec()
+}
+
+// None if the snapshot is malformed
+pub fn decode_snapshot(bytes: &[u8]) -> Option<Snapshot> {
+    let snapshot = Gsnapshot::decode(bytes).ok()?;
+    if snapshot.hash.len() != 32 {
+        return None;
+    }
+
+    let balances = snapshot.balances.into_iter().map(|b| {
+        (b.account.len() == 32).then(|| (NodeID::from_vec(b.account), Amount::from_units(b.balance)))
+    }).collect::<Option<Vec<_>>>()?;
+    let settlements = snapshot.settlements.into_iter().map(|s| {
+        Some((s.height, try_to_data(s.transaction?)?))
+    }).collect::<Option<Vec<_>>>()?;
+    let headers = snapshot.headers.into_iter().map(try_to_header).collect::<Option<Vec<_>>>()?;
+
+    Some(Snapshot {
+        height: snapshot.height,
+        hash: H256::from_slice(&snapshot.hash),
+        balances,
+        settlements,
+        headers,
+    })
+}